@@ -1,7 +1,7 @@
 extern crate libtraceroute;
 
 use libtraceroute::{Traceroute, Config};
-use libtraceroute::util::{Protocol, get_available_interfaces};
+use libtraceroute::util::{Protocol, VlanTag, get_available_interfaces};
 use clap::{Command, arg, value_parser};
 
 
@@ -11,19 +11,20 @@ fn main() {
         .author("Rafael R. G. <rafael@rafaelgaia.com>")
         .about("Traceroute implementation in Rust")
         .arg(arg!(-i --interface <INTERFACE> "Interface to use").value_parser(value_parser!(String)).required(true))
-        .arg(arg!(-p --protocol <PROTOCOL> "Protocol to use (ICMP, UDP or TCP)").value_parser(value_parser!(String)).required(true))
+        .arg(arg!(-p --protocol <PROTOCOL> "Protocol to use (ICMP, UDP, TCP, SCTP or DCCP)").value_parser(value_parser!(String)).required(true))
 		.arg(arg!(-t --timeout [TIMEOUT] "Timeout in milliseconds").value_parser(value_parser!(u64).range(1..)))
         .arg(arg!(-m --maxhops [MAX_HOPS] "Maximum number of hops").value_parser(value_parser!(u32).range(1..=255)))
 		.arg(arg!(-f --frist_ttl [TTL] "First TTL").value_parser(value_parser!(u8).range(1..=255)))
 		.arg(arg!(--mtu [MTU] "Test Max MTU").value_parser(value_parser!(u16).range(80..=1500)))
 		.arg(arg!(--queries [QUERIES] "Max queries").value_parser(value_parser!(u32).range(1..)))
+		.arg(arg!(--vlan [VLAN] "802.1Q VLAN ID to tag probes with").value_parser(value_parser!(u16).range(1..4095)))
 		.arg(arg!(<DESTINATION_IP> "Destination IP address").value_parser(value_parser!(String)))
 		.arg(arg!(<DESTINATION_MAC> "Destination MAC address").value_parser(value_parser!(String)))
 		.get_matches();
 
 	   let available_interfaces = get_available_interfaces();
 
-    let network_interface = match available_interfaces.iter().filter(|i| i.name == matches.get_one::<String>("interface").unwrap().as_str()).next() {
+    let network_interface = match available_interfaces.iter().find(|i| i.name == matches.get_one::<String>("interface").unwrap().as_str()) {
         Some(i) => i.clone(),
         None => panic!("no such interface available")
     };
@@ -32,19 +33,27 @@ fn main() {
 		"ICMP" => Protocol::ICMP,
 		"UDP" => Protocol::UDP,
 		"TCP" => Protocol::TCP,
+		"SCTP" => Protocol::SCTP,
+		"DCCP" => Protocol::DCCP,
 		_ => panic!("no such protocol available")
 	};
-    let mut traceroute_query = Traceroute::new(matches.get_one::<String>("DESTINATION_IP").unwrap(),
-	matches.get_one::<String>("DESTINATION_MAC").unwrap(),
-	 Config::default()
+    let mut config = Config::default()
         .with_port(33480)
         .with_max_hops(*matches.get_one::<u32>("maxhops").unwrap_or(&20))
         .with_first_ttl(*matches.get_one::<u8>("frist_ttl").unwrap_or(&2))
         .with_interface(network_interface)
-		.with_max_mtu(*matches.get_one::<u16>("mtu").unwrap_or(&80))
         .with_number_of_queries(*matches.get_one::<u32>("queries").unwrap_or(&2))
         .with_protocol(protocol)
-        .with_timeout(*matches.get_one::<u64>("timeout").unwrap_or(&1000)));
+        .with_timeout(*matches.get_one::<u64>("timeout").unwrap_or(&1000));
+    if let Some(mtu) = matches.get_one::<u16>("mtu") {
+        config = config.with_max_mtu(*mtu);
+    }
+    if let Some(vlan) = matches.get_one::<u16>("vlan") {
+        config = config.with_vlan(VlanTag::new(*vlan, 0));
+    }
+    let mut traceroute_query = Traceroute::new(matches.get_one::<String>("DESTINATION_IP").unwrap(),
+	matches.get_one::<String>("DESTINATION_MAC").unwrap(),
+	 config);
 
     // Calculate all hops upfront
     let traceroute_result = traceroute_query.perform_traceroute();
@@ -53,7 +62,7 @@ fn main() {
     for hop in traceroute_result {
         print!("{}", hop.ttl);
         for query_result in &hop.query_result {
-            print!(" \t{}ms \t{}\n", query_result.rtt.as_millis(), query_result.addr);
+            println!(" \t{}ms \t{}", query_result.rtt.as_millis(), query_result.addr);
         }
     }
 }
\ No newline at end of file
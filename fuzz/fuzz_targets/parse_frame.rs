@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libtraceroute::response::parse_frame;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(response) = parse_frame(data) {
+        let _ = response.answers(response.responder);
+        let _ = response.mpls_labels();
+    }
+});
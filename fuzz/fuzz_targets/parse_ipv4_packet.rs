@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libtraceroute::response::{parse_ipv4_packet, Ipv4Header};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Ipv4Header::new(data);
+    if let Ok(response) = parse_ipv4_packet(data) {
+        let _ = response.answers(response.responder);
+        let _ = response.mpls_labels();
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libtraceroute::util::transport::{PcapReplay, Transport};
+use std::time::Duration;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut replay) = PcapReplay::from_bytes(data) {
+        let deadline = replay.now() + Duration::from_secs(3600);
+        while replay.recv(deadline).is_some() {}
+    }
+});
@@ -0,0 +1,298 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::{Config, StopReason, TracerouteHop, TracerouteQueryResult};
+use crate::response::ProbeResponse;
+use pnet::util::MacAddr;
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// Number of destinations traced at the same time unless configured otherwise
+const DEFAULT_CONCURRENCY: usize = 16;
+
+/// Result of the trace to one destination of a batch
+pub struct BatchResult {
+    /// Traced destination
+    pub destination: Ipv4Addr,
+    /// Hops on the path, in order
+    pub hops: Vec<TracerouteHop>,
+    /// Reason why the trace stopped
+    pub stop_reason: StopReason,
+}
+
+/// Probe of a trace waiting for its answer
+struct OutstandingProbe {
+    ip_id: u16,
+    packet: Vec<u8>,
+    sent: SystemTime,
+    deadline: SystemTime,
+}
+
+/// State of the trace to one destination
+struct BatchTrace {
+    addr: Ipv4Addr,
+    mac: MacAddr,
+    ttl: u8,
+    results: Vec<TracerouteQueryResult>,
+    hops: Vec<TracerouteHop>,
+    quoted_tos: Option<u8>,
+    outstanding: Option<OutstandingProbe>,
+    stop_reason: Option<StopReason>,
+    started: SystemTime,
+    probes_sent: u32,
+    unresponsive_hops: u32,
+    max_rtt: Option<Duration>,
+}
+
+impl BatchTrace {
+    /// Returns the reason to stop before sending the next probe, if the deadline of the trace
+    /// passed or its probe budget ran out
+    fn check_probe_limits(&self, config: &Config, now: SystemTime) -> Option<StopReason> {
+        if config.deadline.is_some_and(|deadline| now >= self.started + deadline) {
+            Some(StopReason::Deadline)
+        } else if config.probe_budget.is_some_and(|probe_budget| self.probes_sent >= probe_budget) {
+            Some(StopReason::ProbeBudget)
+        } else {
+            None
+        }
+    }
+
+    /// Assembles the hop at the current TTL from the queries completed so far
+    fn push_hop(&mut self, sent_tos: u8) {
+        let results = std::mem::take(&mut self.results);
+        let hop = TracerouteHop::assemble(self.ttl, results, Vec::new(), &mut self.quoted_tos, sent_tos);
+        self.unresponsive_hops = if hop.rtts.iter().all(Option::is_none) { self.unresponsive_hops + 1 } else { 0 };
+        self.hops.push(hop);
+    }
+}
+
+/// Traces many destinations at once through a single channel.
+///
+/// Probes of up to `concurrency` destinations are in flight at the same time, one per
+/// destination, and every reply is matched to its probe by the IPv4 identification it quotes
+/// (or by its source, for replies that quote nothing). Results are yielded by iterating, as soon
+/// as the trace to a destination is complete.
+///
+/// The gap limit, deadline, probe budget and adaptive timeout of the configuration apply to every
+/// destination separately. Hop-count estimation is not supported and is ignored
+pub struct TracerouteBatch {
+    config: Config,
+    concurrency: usize,
+    pending: VecDeque<(Ipv4Addr, MacAddr)>,
+    active: Vec<BatchTrace>,
+    completed: VecDeque<BatchResult>,
+}
+
+impl TracerouteBatch {
+    /// Creates an empty batch. Every destination is traced with `config`
+    pub fn new(config: Config) -> Self {
+        TracerouteBatch {
+            config,
+            concurrency: DEFAULT_CONCURRENCY,
+            pending: VecDeque::new(),
+            active: Vec::new(),
+            completed: VecDeque::new(),
+        }
+    }
+
+    /// Builder: Destination to trace, reached through the next hop at `macstr`
+    pub fn with_destination(mut self, addrstr: &str, macstr: &str) -> Self {
+        self.add_destination(addrstr, macstr);
+        self
+    }
+
+    /// Builder: Maximum number of destinations traced at the same time. Default: 16
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Queues a destination to trace, reached through the next hop at `macstr`
+    pub fn add_destination(&mut self, addrstr: &str, macstr: &str) {
+        self.pending.push_back((
+            Ipv4Addr::from_str(addrstr).unwrap_or(Ipv4Addr::new(0, 0, 0, 0)),
+            MacAddr::from_str(macstr).unwrap_or(MacAddr::zero()),
+        ));
+    }
+
+    /// Starts pending traces, sends the next probe of every trace that has none in flight, then
+    /// handles replies until one arrives or the earliest probe times out
+    fn step(&mut self) {
+        while self.active.len() < self.concurrency {
+            let (addr, mac) = match self.pending.pop_front() {
+                Some(destination) => destination,
+                None => break
+            };
+            self.active.push(BatchTrace {
+                addr, mac, ttl: self.config.ttl.max(1), results: Vec::new(), hops: Vec::new(),
+                quoted_tos: None, outstanding: None, stop_reason: None,
+                started: self.config.channel.now(), probes_sent: 0, unresponsive_hops: 0, max_rtt: None,
+            });
+        }
+
+        for trace in self.active.iter_mut().filter(|trace| trace.outstanding.is_none()) {
+            if let Some(stop_reason) = trace.check_probe_limits(&self.config, self.config.channel.now()) {
+                if !trace.results.is_empty() {
+                    trace.push_hop(self.config.channel.tos());
+                }
+                trace.stop_reason = Some(stop_reason);
+                continue;
+            }
+            self.config.channel.change_ttl(trace.ttl);
+            let packet = self.config.channel.send_to(trace.mac, trace.addr, self.config.mtu.map(usize::from));
+            let sent = self.config.channel.now();
+            let timeout = self.config.adaptive_timeout
+                .map_or(self.config.timeout, |adaptive_timeout| adaptive_timeout.timeout(trace.max_rtt));
+            trace.probes_sent += 1;
+            trace.outstanding = Some(OutstandingProbe {
+                ip_id: u16::from_be_bytes([packet[4], packet[5]]),
+                packet,
+                sent,
+                deadline: sent + timeout,
+            });
+        }
+
+        let deadline = self.active.iter()
+            .filter_map(|trace| trace.outstanding.as_ref().map(|outstanding| outstanding.deadline))
+            .min();
+        if let Some(deadline) = deadline {
+            while let Some((response, received)) = self.config.channel.recv_until(deadline) {
+                if let Some(index) = self.correlate(&response) {
+                    self.complete_query(index, Some(response), received);
+                    break;
+                }
+            }
+        }
+
+        let now = self.config.channel.now();
+        for index in 0..self.active.len() {
+            if self.active[index].outstanding.as_ref().is_some_and(|outstanding| outstanding.deadline <= now) {
+                self.complete_query(index, None, now);
+            }
+        }
+
+        let mut index = 0;
+        while index < self.active.len() {
+            match self.active[index].stop_reason {
+                Some(stop_reason) => {
+                    let trace = self.active.swap_remove(index);
+                    self.completed.push_back(BatchResult { destination: trace.addr, hops: trace.hops, stop_reason });
+                }
+                None => index += 1
+            }
+        }
+    }
+
+    /// Index of the active trace whose probe `response` answers
+    fn correlate(&self, response: &ProbeResponse) -> Option<usize> {
+        let in_flight = |trace: &BatchTrace| trace.outstanding.is_some();
+        match &response.quoted_ip_header {
+            Some(header) => self.active.iter()
+                .position(|trace| trace.outstanding.as_ref().is_some_and(|outstanding| outstanding.ip_id == header.identification))
+                // The identification may have been rewritten (e.g. by a NAT)
+                .or_else(|| self.active.iter().position(|trace| in_flight(trace) && response.answers(trace.addr))),
+            None => self.active.iter().position(|trace| in_flight(trace) && response.responder == trace.addr)
+        }
+    }
+
+    /// Records the answer (or timeout) of the probe in flight of the trace at `index`, and moves
+    /// on to the next hop once all queries of the current one are done
+    fn complete_query(&mut self, index: usize, response: Option<ProbeResponse>, received: SystemTime) {
+        let trace = &mut self.active[index];
+        let outstanding = match trace.outstanding.take() {
+            Some(outstanding) => outstanding,
+            None => return
+        };
+        let rtt = received.duration_since(outstanding.sent).unwrap_or(Duration::from_millis(0));
+        if response.is_some() {
+            trace.max_rtt = Some(trace.max_rtt.map_or(rtt, |max_rtt| max_rtt.max(rtt)));
+        }
+        trace.results.push(TracerouteQueryResult::new(&self.config.channel, &outstanding.packet, response, rtt));
+        if trace.results.len() < self.config.number_of_queries as usize {
+            return;
+        }
+
+        trace.push_hop(self.config.channel.tos());
+        let destination = trace.addr.to_string();
+        let reached = trace.hops.last().is_some_and(|hop| hop.query_result.iter().any(|query_result| query_result.addr == destination));
+        if reached {
+            trace.stop_reason = Some(StopReason::DestinationReached);
+        } else if self.config.gap_limit.is_some_and(|gap_limit| trace.unresponsive_hops >= gap_limit) {
+            trace.stop_reason = Some(StopReason::GapLimit);
+        } else if trace.ttl as u32 >= self.config.max_hops || trace.ttl == u8::MAX {
+            trace.stop_reason = Some(StopReason::MaxHops);
+        } else {
+            trace.ttl += 1;
+        }
+    }
+}
+
+impl Iterator for TracerouteBatch {
+    type Item = BatchResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(result) = self.completed.pop_front() {
+                return Some(result);
+            }
+            if self.pending.is_empty() && self.active.is_empty() {
+                return None;
+            }
+            self.step();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{capture, config, exchange, TARGET};
+    use crate::util::transport::PcapReplay;
+
+    fn trace(config: Config, exchanges: &[(Vec<u8>, Vec<u8>)]) -> BatchResult {
+        let replay = PcapReplay::from_bytes(&capture(exchanges)).unwrap();
+        let mut batch = TracerouteBatch::new(config.with_transport(Box::new(replay)))
+            .with_destination("192.0.2.1", "02:00:00:00:00:02");
+        let result = batch.next().unwrap();
+        assert!(batch.next().is_none());
+        result
+    }
+
+    #[test]
+    fn stops_when_destination_reached() {
+        let result = trace(config(5), &[exchange([10, 0, 0, 1], 11, 0), exchange(TARGET, 3, 3)]);
+        assert_eq!(result.hops.len(), 2);
+        assert_eq!(result.hops[1].query_result[0].addr, "192.0.2.1");
+        assert_eq!(result.stop_reason, StopReason::DestinationReached);
+    }
+
+    #[test]
+    fn honours_probe_budget() {
+        let routers = [[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 3]];
+        let result = trace(config(5).with_probe_budget(2), &routers.map(|router| exchange(router, 11, 0)));
+        assert_eq!(result.hops.len(), 2);
+        assert_eq!(result.stop_reason, StopReason::ProbeBudget);
+    }
+
+    #[test]
+    fn honours_gap_limit() {
+        let result = trace(config(10).with_gap_limit(2), &[exchange([10, 0, 0, 1], 11, 0)]);
+        assert_eq!(result.hops.iter().map(|hop| hop.rtts[0].is_some()).collect::<Vec<_>>(), [true, false, false]);
+        assert_eq!(result.stop_reason, StopReason::GapLimit);
+    }
+}
@@ -0,0 +1,284 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::{Config, StopReason, Traceroute, TracerouteHop};
+use pnet::util::MacAddr;
+use std::collections::{HashSet, VecDeque};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// TTL at which traces start unless configured otherwise
+const DEFAULT_INITIAL_TTL: u8 = 6;
+/// Length of the destination prefixes of the global stop set unless configured otherwise
+const DEFAULT_PREFIX_LENGTH: u8 = 24;
+
+/// Result of the trace to one destination
+pub struct DoubletreeResult {
+    /// Traced destination
+    pub destination: Ipv4Addr,
+    /// Probed hops, in order of TTL. Hops skipped thanks to the stop sets are missing
+    pub hops: Vec<TracerouteHop>,
+    /// Reason why forward probing stopped
+    pub stop_reason: StopReason,
+    /// TTL at which backward probing met an interface of the local stop set, `None` if it went
+    /// down to the first TTL
+    pub backward_stop_ttl: Option<u8>,
+    /// Number of probes sent for this destination
+    pub probes_sent: u32,
+}
+
+/// Traces many destinations from one vantage point with the Doubletree algorithm.
+///
+/// Every trace starts at an initial TTL in the middle of the path. Forward probing goes towards
+/// the destination and stops on reaching it, or on meeting an interface already seen on the way to
+/// the same destination prefix (the global stop set). Backward probing then goes towards the source
+/// and stops on meeting any interface already seen by this instance (the local stop set). The
+/// global stop set can be seeded and exported to share it between vantage points
+pub struct Doubletree {
+    traceroute: Traceroute,
+    initial_ttl: u8,
+    prefix_length: u8,
+    destinations: VecDeque<(Ipv4Addr, MacAddr)>,
+    local_stop_set: HashSet<Ipv4Addr>,
+    global_stop_set: HashSet<(Ipv4Addr, Ipv4Addr)>,
+}
+
+impl Doubletree {
+    /// Creates new instance of Doubletree. Every destination is traced with `config`
+    pub fn new(config: Config) -> Self {
+        Doubletree {
+            traceroute: Traceroute::new("0.0.0.0", "00:00:00:00:00:00", config),
+            initial_ttl: DEFAULT_INITIAL_TTL,
+            prefix_length: DEFAULT_PREFIX_LENGTH,
+            destinations: VecDeque::new(),
+            local_stop_set: HashSet::new(),
+            global_stop_set: HashSet::new(),
+        }
+    }
+
+    /// Builder: Destination to trace, reached through the next hop at `macstr`
+    pub fn with_destination(mut self, addrstr: &str, macstr: &str) -> Self {
+        self.add_destination(addrstr, macstr);
+        self
+    }
+
+    /// Builder: TTL at which traces start, probing forward then backward. Default: 6
+    pub fn with_initial_ttl(mut self, initial_ttl: u8) -> Self {
+        self.initial_ttl = initial_ttl.max(1);
+        self
+    }
+
+    /// Builder: Length of the destination prefixes the global stop set is keyed by. Paths to
+    /// destinations of the same prefix are assumed to share their last hops. Default: 24
+    pub fn with_prefix_length(mut self, prefix_length: u8) -> Self {
+        self.prefix_length = prefix_length.min(32);
+        self
+    }
+
+    /// Builder: Global stop set of (interface, destination prefix) pairs explored beforehand,
+    /// e.g. by other vantage points
+    pub fn with_global_stop_set(mut self, global_stop_set: HashSet<(Ipv4Addr, Ipv4Addr)>) -> Self {
+        self.global_stop_set = global_stop_set;
+        self
+    }
+
+    /// Queues a destination to trace, reached through the next hop at `macstr`
+    pub fn add_destination(&mut self, addrstr: &str, macstr: &str) {
+        self.destinations.push_back((
+            Ipv4Addr::from_str(addrstr).unwrap_or(Ipv4Addr::new(0, 0, 0, 0)),
+            MacAddr::from_str(macstr).unwrap_or(MacAddr::zero()),
+        ));
+    }
+
+    /// Interfaces seen so far by this instance
+    pub fn local_stop_set(&self) -> &HashSet<Ipv4Addr> {
+        &self.local_stop_set
+    }
+
+    /// (interface, destination prefix) pairs seen so far while probing forward
+    pub fn global_stop_set(&self) -> &HashSet<(Ipv4Addr, Ipv4Addr)> {
+        &self.global_stop_set
+    }
+
+    /// Traces the path to `addr` and updates the stop sets
+    fn trace(&mut self, addr: Ipv4Addr, mac: MacAddr) -> DoubletreeResult {
+        self.traceroute.reset(addr, mac);
+        let prefix = self.prefix(addr);
+        let first_ttl = self.traceroute.config.ttl.max(1);
+        let max_ttl = self.traceroute.config.max_hops.min(u8::MAX as u32) as u8;
+        let initial_ttl = self.initial_ttl.max(first_ttl).min(max_ttl.max(first_ttl));
+        let mut hops = Vec::new();
+
+        let mut ttl = initial_ttl;
+        let mut unresponsive_hops = 0;
+        let stop_reason = loop {
+            if let Some(stop_reason) = self.traceroute.check_probe_limits() {
+                break stop_reason;
+            }
+            let hop = self.probe_hop(ttl);
+            let responders = Self::responders(&hop);
+            let joined = responders.iter().any(|responder| self.global_stop_set.contains(&(*responder, prefix)));
+            for responder in &responders {
+                self.local_stop_set.insert(*responder);
+                self.global_stop_set.insert((*responder, prefix));
+            }
+            unresponsive_hops = if responders.is_empty() { unresponsive_hops + 1 } else { 0 };
+            hops.push(hop);
+
+            if responders.contains(&addr) {
+                break StopReason::DestinationReached;
+            } else if joined {
+                break StopReason::GlobalStopSet;
+            } else if self.traceroute.config.gap_limit.is_some_and(|gap_limit| unresponsive_hops >= gap_limit) {
+                break StopReason::GapLimit;
+            } else if ttl >= max_ttl {
+                break StopReason::MaxHops;
+            }
+            ttl += 1;
+        };
+
+        self.traceroute.quoted_tos = None;
+        let mut backward_stop_ttl = None;
+        for ttl in (first_ttl..initial_ttl).rev() {
+            if self.traceroute.check_probe_limits().is_some() {
+                break;
+            }
+            let hop = self.probe_hop(ttl);
+            let responders = Self::responders(&hop);
+            let known = responders.iter().any(|responder| self.local_stop_set.contains(responder));
+            self.local_stop_set.extend(responders);
+            hops.push(hop);
+            if known {
+                backward_stop_ttl = Some(ttl);
+                break;
+            }
+        }
+
+        hops.sort_by_key(|hop| hop.ttl);
+        self.traceroute.stop_reason = Some(stop_reason);
+        DoubletreeResult { destination: addr, hops, stop_reason, backward_stop_ttl, probes_sent: self.traceroute.probes_sent }
+    }
+
+    /// Probes the hop at `ttl`
+    fn probe_hop(&mut self, ttl: u8) -> TracerouteHop {
+        self.traceroute.config.channel.change_ttl(ttl);
+        self.traceroute.calculate_next_hop()
+    }
+
+    /// Distinct nodes that answered the queries of `hop`
+    fn responders(hop: &TracerouteHop) -> Vec<Ipv4Addr> {
+        let mut responders = Vec::new();
+        for responder in hop.query_result.iter().filter_map(|query_result| query_result.response.as_ref()).map(|response| response.responder) {
+            if !responders.contains(&responder) {
+                responders.push(responder);
+            }
+        }
+        responders
+    }
+
+    /// Destination prefix `addr` belongs to
+    fn prefix(&self, addr: Ipv4Addr) -> Ipv4Addr {
+        let mask = u32::MAX.checked_shl(32 - self.prefix_length as u32).unwrap_or(0);
+        Ipv4Addr::from(u32::from(addr) & mask)
+    }
+}
+
+impl Iterator for Doubletree {
+    type Item = DoubletreeResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (addr, mac) = self.destinations.pop_front()?;
+        Some(self.trace(addr, mac))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{capture, config, exchange_to};
+    use crate::util::transport::PcapReplay;
+
+    const NEXT_HOP: &str = "02:00:00:00:00:02";
+
+    /// Doubletree starting at TTL 3 over the recorded `exchanges`
+    fn doubletree(exchanges: &[(Vec<u8>, Vec<u8>)]) -> Doubletree {
+        let replay = PcapReplay::from_bytes(&capture(exchanges)).unwrap();
+        Doubletree::new(config(8).with_transport(Box::new(replay))).with_initial_ttl(3)
+    }
+
+    fn ttls(result: &DoubletreeResult) -> Vec<u8> {
+        result.hops.iter().map(|hop| hop.ttl).collect()
+    }
+
+    #[test]
+    fn stops_forward_probing_on_global_stop_set() {
+        let target = [192, 0, 2, 1];
+        let exchanges = [exchange_to(target, [10, 0, 0, 3], 11, 0), exchange_to(target, [10, 0, 0, 4], 11, 0),
+                         exchange_to(target, [10, 0, 0, 2], 11, 0), exchange_to(target, [10, 0, 0, 1], 11, 0)];
+        let seen = HashSet::from([(Ipv4Addr::new(10, 0, 0, 4), Ipv4Addr::new(192, 0, 2, 0))]);
+        let mut doubletree = doubletree(&exchanges).with_global_stop_set(seen).with_destination("192.0.2.1", NEXT_HOP);
+
+        let result = doubletree.next().unwrap();
+        assert_eq!(result.stop_reason, StopReason::GlobalStopSet);
+        assert_eq!(ttls(&result), [1, 2, 3, 4]);
+        assert_eq!(result.backward_stop_ttl, None);
+        assert_eq!(result.probes_sent, 4);
+        assert!(doubletree.global_stop_set().contains(&(Ipv4Addr::new(10, 0, 0, 3), Ipv4Addr::new(192, 0, 2, 0))));
+        assert!(doubletree.next().is_none());
+    }
+
+    #[test]
+    fn shares_stop_sets_between_destinations() {
+        let (first, other_prefix, same_prefix) = ([192, 0, 2, 1], [198, 18, 0, 1], [192, 0, 2, 99]);
+        let exchanges = [
+            exchange_to(first, [10, 0, 0, 3], 11, 0), exchange_to(first, first, 3, 3),
+            exchange_to(first, [10, 0, 0, 2], 11, 0), exchange_to(first, [10, 0, 0, 1], 11, 0),
+            exchange_to(other_prefix, [10, 0, 0, 3], 11, 0), exchange_to(other_prefix, other_prefix, 3, 3),
+            exchange_to(other_prefix, [10, 0, 0, 2], 11, 0),
+            exchange_to(same_prefix, [10, 0, 0, 3], 11, 0), exchange_to(same_prefix, [10, 0, 0, 2], 11, 0),
+        ];
+        let results: Vec<DoubletreeResult> = doubletree(&exchanges)
+            .with_destination("192.0.2.1", NEXT_HOP)
+            .with_destination("198.18.0.1", NEXT_HOP)
+            .with_destination("192.0.2.99", NEXT_HOP)
+            .collect();
+
+        assert_eq!(results[0].stop_reason, StopReason::DestinationReached);
+        assert_eq!(ttls(&results[0]), [1, 2, 3, 4]);
+        assert_eq!(results[0].backward_stop_ttl, None);
+
+        // 10.0.0.3 was only seen on the way to 192.0.2.0/24, but 10.0.0.2 was seen locally
+        assert_eq!(results[1].stop_reason, StopReason::DestinationReached);
+        assert_eq!(ttls(&results[1]), [2, 3, 4]);
+        assert_eq!(results[1].backward_stop_ttl, Some(2));
+        assert_eq!(results[1].probes_sent, 3);
+
+        assert_eq!(results[2].stop_reason, StopReason::GlobalStopSet);
+        assert_eq!(ttls(&results[2]), [2, 3]);
+        assert_eq!(results[2].backward_stop_ttl, Some(2));
+    }
+
+    #[test]
+    fn masks_destination_prefixes() {
+        let addr = Ipv4Addr::new(192, 0, 2, 99);
+        let prefix = |prefix_length: u8| Doubletree::new(config(1)).with_prefix_length(prefix_length).prefix(addr);
+        assert_eq!(prefix(24), Ipv4Addr::new(192, 0, 2, 0));
+        assert_eq!(prefix(20), Ipv4Addr::new(192, 0, 0, 0));
+        assert_eq!(prefix(32), addr);
+        assert_eq!(prefix(40), addr);
+        assert_eq!(prefix(0), Ipv4Addr::UNSPECIFIED);
+    }
+}
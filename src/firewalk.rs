@@ -0,0 +1,163 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::{Config, Traceroute};
+use crate::util::Protocol;
+use pnet::packet::icmp::{IcmpCode, IcmpTypes};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Fate of the probes sent one hop past the gateway
+pub enum FirewalkVerdict {
+    /// The gateway forwarded the probe: it expired at (or reached) the given node behind it
+    Passed(Ipv4Addr),
+    /// The probe was rejected with an ICMP destination unreachable by the given node
+    Rejected(Ipv4Addr, IcmpCode),
+    /// Every probe went unanswered, e.g. silently dropped by the gateway
+    Filtered,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Result of firewalking a single port
+pub struct FirewalkPort {
+    /// Protocol of the probes
+    pub protocol: Protocol,
+    /// Destination port of the probes (ignored for ICMP)
+    pub port: u16,
+    /// Whether the probes got past the gateway
+    pub verdict: FirewalkVerdict,
+    /// Round-Trip Time of the answer, zero if filtered
+    pub rtt: Duration,
+}
+
+/// Firewalk instance finding out which ports and protocols a gateway lets through.
+///
+/// The hop count to the gateway is discovered by tracing towards the target until the gateway
+/// answers. Probes to every port are then sent towards the target with a TTL of one past the
+/// gateway, so that a probe the gateway forwards expires right behind it
+pub struct Firewalk {
+    traceroute: Traceroute,
+    gateway: Ipv4Addr,
+    gateway_ttl: Option<u8>,
+    ports: Vec<(Protocol, u16)>,
+}
+
+impl Firewalk {
+    /// Creates new instance of Firewalk for the gateway `gatewaystr` on the path to `addrstr`
+    pub fn new(addrstr: &str, gatewaystr: &str, macstr: &str, config: Config) -> Self {
+        Firewalk {
+            traceroute: Traceroute::new(addrstr, macstr, config),
+            gateway: Ipv4Addr::from_str(gatewaystr).unwrap_or(Ipv4Addr::new(0, 0, 0, 0)),
+            gateway_ttl: None,
+            ports: Vec::new(),
+        }
+    }
+
+    /// Builder: Port to test with the given protocol. Can be called repeatedly
+    pub fn with_port(mut self, protocol: Protocol, port: u16) -> Self {
+        self.ports.push((protocol, port));
+        self
+    }
+
+    /// Builder: Ports to test with the given protocol
+    pub fn with_ports(mut self, protocol: Protocol, ports: &[u16]) -> Self {
+        self.ports.extend(ports.iter().map(|port| (protocol, *port)));
+        self
+    }
+
+    /// Returns the number of hops to the gateway, tracing towards the target on first use.
+    /// `None` if the gateway didn't show up on the path
+    pub fn gateway_hop_count(&mut self) -> Option<u8> {
+        if self.gateway_ttl.is_none() {
+            let gateway = self.gateway.to_string();
+            self.gateway_ttl = self.traceroute.by_ref()
+                .find(|hop| hop.query_result.iter().any(|query_result| query_result.addr == gateway))
+                .map(|hop| hop.ttl);
+        }
+        self.gateway_ttl
+    }
+
+    /// Tests every configured port and returns the verdicts in the same order
+    pub fn perform_firewalk(&mut self) -> Result<Vec<FirewalkPort>, &'static str> {
+        let gateway_ttl = self.gateway_hop_count().ok_or("gateway not found on the path to the target")?;
+        let probe_ttl = gateway_ttl.checked_add(1).ok_or("gateway is too far away")?;
+        let ports = self.ports.clone();
+        Ok(ports.into_iter()
+            .map(|(protocol, port)| self.firewalk_port(protocol, port, probe_ttl))
+            .collect())
+    }
+
+    /// Sends the configured number of queries to `port` and returns the first conclusive answer
+    fn firewalk_port(&mut self, protocol: Protocol, port: u16, ttl: u8) -> FirewalkPort {
+        let traceroute = &mut self.traceroute;
+        traceroute.config.channel.change_protocol(protocol);
+        for _ in 0..traceroute.config.number_of_queries {
+            traceroute.config.channel.change_ttl(ttl);
+            traceroute.config.channel.change_port(port);
+            let result = traceroute.get_next_query_result();
+            let response = match &result.response {
+                Some(response) => response,
+                None => continue
+            };
+            let verdict = match response.icmp_type() {
+                _ if response.responder == traceroute.addr => FirewalkVerdict::Passed(response.responder),
+                // The gateway itself expiring the probe means it is further away than it was
+                Some(IcmpTypes::TimeExceeded) if response.responder != self.gateway =>
+                    FirewalkVerdict::Passed(response.responder),
+                Some(IcmpTypes::DestinationUnreachable) =>
+                    FirewalkVerdict::Rejected(response.responder, response.icmp_code().unwrap_or(IcmpCode(0))),
+                _ => continue
+            };
+            return FirewalkPort { protocol, port, verdict, rtt: result.rtt };
+        }
+        FirewalkPort { protocol, port, verdict: FirewalkVerdict::Filtered, rtt: Duration::from_millis(0) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{capture, config, exchange};
+    use crate::util::transport::PcapReplay;
+
+    const GATEWAY: [u8; 4] = [10, 0, 0, 2];
+
+    /// Verdicts on four UDP ports behind the gateway at hop 2, the first three answered with
+    /// `answers` and the last one unanswered
+    fn verdicts(answers: [(Vec<u8>, Vec<u8>); 3]) -> Vec<FirewalkVerdict> {
+        let mut exchanges = vec![exchange([10, 0, 0, 1], 11, 0), exchange(GATEWAY, 11, 0)];
+        exchanges.extend(answers);
+        let replay = PcapReplay::from_bytes(&capture(&exchanges)).unwrap();
+        let mut firewalk = Firewalk::new("192.0.2.1", "10.0.0.2", "02:00:00:00:00:02",
+                                         config(5).with_transport(Box::new(replay)))
+            .with_ports(Protocol::UDP, &[53, 80, 443, 8080]);
+        assert_eq!(firewalk.gateway_hop_count(), Some(2));
+        firewalk.perform_firewalk().unwrap().into_iter().map(|port| port.verdict).collect()
+    }
+
+    #[test]
+    fn classifies_ports() {
+        let verdicts = verdicts([exchange([10, 0, 0, 3], 11, 0), exchange(GATEWAY, 3, 13), exchange(GATEWAY, 11, 0)]);
+        assert_eq!(verdicts, [
+            FirewalkVerdict::Passed(Ipv4Addr::new(10, 0, 0, 3)),
+            FirewalkVerdict::Rejected(GATEWAY.into(), IcmpCode(13)),
+            FirewalkVerdict::Filtered,
+            FirewalkVerdict::Filtered,
+        ]);
+    }
+}
@@ -21,11 +21,48 @@
 //! `libtraceroute` sends and receives packets at a data link layer, which makes it
 //! flexible and highly customisable. The library allows to configure the following parameters:
 //! - Port
-//! - Timeout per query (in _ms_)
+//! - Timeout per query (in _ms_), fixed or adapted to the RTTs observed on previous hops
+//! - Interval between probes and process-wide packets-per-second limit
 //! - Maximum number of hops
 //! - Number of queries per hop
+//! - Stop conditions: consecutive unresponsive hops, overall deadline and probe budget
+//! - Hop-count estimation from the destination's reply TTL, optionally refined by binary search
 //! - Network interface
-//! - Protocol (UDP, TCP, ICMP)
+//! - Protocol (UDP, TCP, ICMP, SCTP, DCCP)
+//! - 802.1Q VLAN tag, optionally stacked (QinQ)
+//! - GRE or IP-in-IP encapsulation towards a tunnel endpoint
+//! - Transport (live capture on an interface, or replay of a pcap/pcapng file)
+//! - Capture file recording every sent and received frame (pcapng)
+//! - IPv4 Record Route and Timestamp options, decoded from echo replies and quoted headers
+//! - DSCP and ECN marking, with detection of hops that rewrite it
+//! - Options of TCP SYN probes
+//! - Application payload of UDP probes (DNS, NTP, QUIC), with detection of the application's answer
+//! - Flags of TCP probes (ACK, FIN, NULL, XMAS...), with classification of the destination's answer
+//!
+//! Beyond iterating over the hops, single probes can be sent with [`Traceroute::probe`] and
+//! hops probed again with [`Traceroute::probe_hop`] to build custom measurement strategies.
+//!
+//! A [`Monitor`](monitor::Monitor) mode probes the path continuously like mtr, keeping loss, RTT
+//! and jitter statistics for every hop.
+//!
+//! A [`TracerouteBatch`](batch::TracerouteBatch) traces many destinations at once, sharing a
+//! single channel and receive loop between them.
+//!
+//! A [`Doubletree`](doubletree::Doubletree) traces many destinations while avoiding to probe
+//! the same interfaces over and over, by starting every trace mid-path and stopping it as soon as
+//! it joins a path already explored.
+//!
+//! A [`Yarrp`](yarrp::Yarrp) sweep probes every (target, TTL) pair in random order at a fixed
+//! rate without waiting for replies, attributing them from the fields encoded in the probes.
+//!
+//! A [`Firewalk`](firewalk::Firewalk) mode finds out which ports and protocols a gateway on the
+//! path lets through, by sending probes that expire one hop behind it.
+//!
+//! Hops expose RTT statistics over their queries (min/avg/max/median/stddev, loss), and
+//! [`segment_deltas`](statistics::segment_deltas) gives the latency added between consecutive hops.
+//!
+//! Every reply that quotes the probe is compared against the probe as it was sent, revealing
+//! middleboxes (NATs, MSS clampers, checksum rewriters...) on the path.
 //!
 //! The library is based on [pnet](https://github.com/libpnet/libpnet) which allows
 //! to work at Layer 2 (Data link) without root privileges on MacOS and Windows, but still requires
@@ -95,19 +132,71 @@
 //!     }
 //! }
 //! ```
+//!
+//! ### Re-running a trace from a capture:
+//!
+//! ```rust,no_run
+//! extern crate libtraceroute;
+//!
+//! use libtraceroute::{Traceroute, Config};
+//! use libtraceroute::util::transport::PcapReplay;
+//!
+//! fn main() {
+//!     let replay = PcapReplay::open("trace.pcapng").expect("unable to read capture");
+//!
+//!     let traceroute_query = Traceroute::new("93.184.216.34", "00:00:00:00:00:00",
+//!         Config::from_transport(Box::new(replay)));
+//!
+//!     for hop in traceroute_query {
+//!         for query_result in &hop.query_result {
+//!             println!("{} \t{}ms \t{}", hop.ttl, query_result.rtt.as_millis(), query_result.addr);
+//!         }
+//!     }
+//! }
+//! ```
 
 
 extern crate pnet;
 
 /// Miscellaneous utilities for for traceroute
 pub mod util;
+/// Decoding of ICMP responses to traceroute probes
+pub mod response;
+/// Detection of middleboxes by comparing probes with the headers quoted back by routers
+pub mod middlebox;
+/// Firewalking: finding out which ports and protocols a gateway forwards
+pub mod firewalk;
+/// Continuous, mtr-style monitoring of a path with per-hop statistics
+pub mod monitor;
+/// RTT statistics of hops and of the segments between them
+pub mod statistics;
+/// Tracing of many destinations at once over a single channel
+pub mod batch;
+/// Doubletree: tracing many destinations while skipping the parts of the paths already explored
+pub mod doubletree;
+/// Stateless, randomized high-speed topology sweeps in the style of Yarrp
+pub mod yarrp;
 
 use pnet::datalink::NetworkInterface;
 use pnet::util::MacAddr;
 use std::net::Ipv4Addr;
+use std::io::Write;
 use std::str::FromStr;
-use std::time::Duration;
-use crate::util::Protocol;
+use std::time::{Duration, SystemTime};
+use crate::util::{Protocol, VlanTag};
+use crate::util::transport::Transport;
+use crate::util::packet_builder::{Encapsulation, IpOption, TcpSynOptions};
+use crate::util::payload::ApplicationPayload;
+use crate::response::{ProbeResponse, ResponseKind};
+use pnet::packet::icmp::{IcmpCode, IcmpTypes};
+use pnet::packet::tcp::TcpFlags;
+use crate::middlebox::{HeaderField, Modification};
+use crate::statistics::RttStatistics;
+
+/// Common initial TTLs of replies, used to infer how many hops they crossed
+const INITIAL_TTLS: [u8; 3] = [64, 128, 255];
+/// Hops probed beyond a distance inferred from a reply TTL, as the reply may take a shorter path
+const REPLY_TTL_MARGIN: u8 = 2;
 
 /// Traceroute instance containing destination address and configurations
 pub struct Traceroute {
@@ -115,6 +204,13 @@ pub struct Traceroute {
 	mac: MacAddr,
     config: Config,
     done: bool,
+    quoted_tos: Option<u8>,
+    started: Option<SystemTime>,
+    probes_sent: u32,
+    unresponsive_hops: u32,
+    stop_reason: Option<StopReason>,
+    max_rtt: Option<Duration>,
+    hop_count: Option<u8>,
 }
 
 /// Traceroute configurations
@@ -123,8 +219,13 @@ pub struct Config {
     max_hops: u32,
     number_of_queries: u32,
     ttl: u8,
-	mtu: u16,
+	mtu: Option<u16>,
     timeout: Duration,
+    adaptive_timeout: Option<AdaptiveTimeout>,
+    gap_limit: Option<u32>,
+    deadline: Option<Duration>,
+    probe_budget: Option<u32>,
+    hop_count_estimation: Option<HopCountEstimation>,
     channel: util::Channel,
 }
 
@@ -134,6 +235,16 @@ pub struct TracerouteHop {
     pub ttl: u8,
     /// Traceroute query results
     pub query_result: Vec<TracerouteQueryResult>,
+    /// RTT of every query in the order they were sent, `None` for queries that timed out. Unlike
+    /// `query_result`, answers from the same responder are not deduplicated
+    pub rtts: Vec<Option<Duration>>,
+    /// Answers to probes of earlier hops that arrived after their timeout, while this hop was
+    /// probed
+    pub late_replies: Vec<LateReply>,
+    /// Whether the TOS byte quoted at this hop differs from the one quoted at the previous
+    /// responding hop (or from the one sent, for the first), i.e. whether the marking was
+    /// rewritten between them
+    pub tos_changed: bool,
 }
 
 /// Result of a single query execution - IP and RTT
@@ -142,16 +253,163 @@ pub struct TracerouteQueryResult {
     pub rtt: Duration,
     /// IP address of a remote node
     pub addr: String,
+    /// Decoded response, `None` if the query timed out
+    pub response: Option<ProbeResponse>,
+    /// TOS byte of the probe as sent and as quoted back, if the response quotes it
+    pub tos: Option<TosObservation>,
+    /// Fields of the probe that were modified before reaching the responder
+    pub modifications: Vec<Modification>,
+    /// Whether the application at the destination answered the probe's application payload
+    pub application_answered: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// Answer to a probe that arrived after the probe timed out
+pub struct LateReply {
+    /// TTL of the probe
+    pub ttl: u8,
+    /// Round-Trip Time
+    pub rtt: Duration,
+    /// Decoded response
+    pub response: ProbeResponse,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Timeout policy deriving the wait for each probe from the RTTs observed so far: the highest
+/// RTT times `multiplier`, bounded by `floor` and `ceiling`. Probes wait for `ceiling` until the
+/// first answer
+pub struct AdaptiveTimeout {
+    /// Shortest wait
+    pub floor: Duration,
+    /// Longest wait
+    pub ceiling: Duration,
+    /// Factor applied to the highest observed RTT
+    pub multiplier: f64,
+}
+
+impl Default for AdaptiveTimeout {
+    fn default() -> Self {
+        AdaptiveTimeout { floor: Duration::from_millis(100), ceiling: Duration::from_secs(3), multiplier: 3.0 }
+    }
+}
+
+impl AdaptiveTimeout {
+    /// Creates a policy with `floor` and `ceiling` in milliseconds
+    pub fn new(floor: u64, ceiling: u64, multiplier: f64) -> Self {
+        AdaptiveTimeout { floor: Duration::from_millis(floor), ceiling: Duration::from_millis(ceiling.max(floor)), multiplier }
+    }
+
+    /// Wait for the next probe given the highest RTT observed so far. The ceiling wins over the
+    /// floor, and a wait too long to represent is capped at the ceiling
+    pub fn timeout(&self, max_rtt: Option<Duration>) -> Duration {
+        match max_rtt {
+            Some(max_rtt) => Duration::try_from_secs_f64(max_rtt.as_secs_f64() * self.multiplier.max(0.0))
+                .unwrap_or(self.ceiling)
+                .max(self.floor)
+                .min(self.ceiling),
+            None => self.ceiling
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How the number of hops to the destination is estimated before tracing, to bound the probed TTLs
+pub enum HopCountEstimation {
+    /// Infers the distance from the remaining TTL of the destination's reply to a single probe
+    /// sent with the maximum TTL, assuming an initial TTL of 64, 128 or 255. As the reply may
+    /// take a shorter path back, a couple of hops beyond the estimate are still probed
+    ReplyTtl,
+    /// Starts from the inferred distance and refines it with a binary search for the lowest TTL
+    /// at which the destination answers, one probe per TTL tried
+    BinarySearch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Reason why a traceroute stopped
+pub enum StopReason {
+    /// The destination answered
+    DestinationReached,
+    /// The maximum number of hops was probed
+    MaxHops,
+    /// The configured number of consecutive hops went completely unanswered
+    GapLimit,
+    /// The overall deadline of the trace passed
+    Deadline,
+    /// The configured number of probes was sent
+    ProbeBudget,
+    /// The trace joined a path already explored towards the destination (see
+    /// [`Doubletree`](doubletree::Doubletree))
+    GlobalStopSet,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Type of service byte of a probe as sent and as seen by the responder
+pub struct TosObservation {
+    /// TOS byte set on the probe
+    pub sent: u8,
+    /// TOS byte in the IPv4 header quoted by the responder
+    pub quoted: u8,
+}
+
+impl TosObservation {
+    /// Checks whether the DSCP marking was rewritten on the way
+    pub fn dscp_changed(&self) -> bool {
+        self.sent >> 2 != self.quoted >> 2
+    }
+
+    /// Checks whether the ECN field was rewritten on the way
+    pub fn ecn_changed(&self) -> bool {
+        self.sent & 0x3 != self.quoted & 0x3
+    }
+
+    /// Checks whether an ECN-capable probe arrived with the ECN field cleared
+    pub fn ecn_bleached(&self) -> bool {
+        self.sent & 0x3 != 0 && self.quoted & 0x3 == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How the destination (or a firewall in front of it) answered a probe
+pub enum DestinationResponse {
+    /// TCP segment with SYN and ACK set, e.g. an open port answering a SYN
+    SynAck,
+    /// TCP segment with RST set, e.g. a closed port or an unfiltered port answering an ACK
+    Rst,
+    /// TCP segment with any other flags
+    OtherTcp(u8),
+    /// UDP datagram, i.e. the application behind the probed port answered
+    Udp,
+    /// SCTP packet starting with a chunk of the given type, e.g. 2 for INIT-ACK or 6 for ABORT
+    Sctp(u8),
+    /// DCCP packet of the given type, e.g. 1 for a Response or 7 for a Reset
+    Dccp(u8),
+    /// ICMP echo reply
+    EchoReply,
+    /// ICMP destination unreachable with the given code, e.g. administratively prohibited
+    Unreachable(IcmpCode),
+    /// No answer, e.g. a firewall silently dropping the probe
+    Silence,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Config {port: 33434, max_hops: 30, number_of_queries: 3, ttl: 1, mtu: 80, timeout: Duration::from_secs(1), channel: Default::default()}
+        Config::with_channel(Default::default())
     }
 }
 
 impl Config {
-    /// Builder: Port for traceroute. Will be incremented on every query (except for TCP-based traceroute)
+    fn with_channel(channel: util::Channel) -> Self {
+        Config {port: 33434, max_hops: 30, number_of_queries: 3, ttl: 1, mtu: None, timeout: Duration::from_secs(1), adaptive_timeout: None,
+            gap_limit: None, deadline: None, probe_budget: None, hop_count_estimation: None, channel}
+    }
+
+    /// Creates a default configuration sending and receiving through `transport`, e.g. a
+    /// [`PcapReplay`](util::transport::PcapReplay), without looking up any network interface
+    pub fn from_transport(transport: Box<dyn Transport>) -> Self {
+        Config::with_channel(util::Channel::from_transport(transport, 33434, 1))
+    }
+
+    /// Builder: Port for traceroute. Will be incremented on every query, starting over from it past 65535 (except for TCP, SCTP and DCCP-based traceroute)
     pub fn with_port(mut self, port: u16) -> Self {
         self.port = port;
         self
@@ -169,7 +427,7 @@ impl Config {
         self
     }
 
-    /// Builder: Protocol. Supported: UDP, TCP, ICMP
+    /// Builder: Protocol. Supported: UDP, TCP, ICMP, SCTP, DCCP
     pub fn with_protocol(mut self, protocol: Protocol) -> Self {
         self.channel.change_protocol(protocol);
         self
@@ -177,7 +435,7 @@ impl Config {
 
     /// Builder: Interface that will be used for sending and receiving packets
     pub fn with_interface(mut self, network_interface: NetworkInterface) -> Self {
-        self.channel = util::Channel::new(network_interface, self.port, self.ttl);
+        self.channel.change_interface(network_interface, self.port, self.ttl);
         self
     }
 
@@ -193,25 +451,264 @@ impl Config {
         self
     }
 
-	/// Builder: Max Mtu
+	/// Builder: Max Mtu. Probes are padded to this size; by default UDP and ICMP probes are
+	/// 80 bytes long and TCP, SCTP and DCCP probes carry no payload
 	pub fn with_max_mtu(mut self, max_mtu: u16) -> Self {
-		self.mtu = max_mtu;
+		self.mtu = Some(max_mtu);
 		self
 	}
+
+    /// Builder: IPv4 options (Record Route, Timestamp) carried by every probe. The recorded
+    /// entries are available from [`ProbeResponse::record_route`] and [`ProbeResponse::timestamps`]
+    pub fn with_ip_options(mut self, ip_options: Vec<IpOption>) -> Self {
+        self.channel.change_ip_options(ip_options);
+        self
+    }
+
+    /// Builder: Options carried by TCP SYN probes. Default: Linux-like MSS, SACK permitted,
+    /// timestamps and window scale
+    pub fn with_tcp_options(mut self, tcp_options: TcpSynOptions) -> Self {
+        self.channel.change_tcp_options(tcp_options);
+        self
+    }
+
+    /// Builder: Flags of TCP probes, e.g. `TcpFlags::ACK` or `TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG`
+    /// for firewall testing. Options are only sent with SYN. Default: SYN
+    pub fn with_tcp_flags(mut self, tcp_flags: u8) -> Self {
+        self.channel.change_tcp_flags(tcp_flags);
+        self
+    }
+
+    /// Builder: Application payload (DNS query, NTP request, QUIC Initial) carried by UDP probes,
+    /// which are then sent to the service port rather than to incrementing high ports
+    pub fn with_udp_payload(mut self, payload: ApplicationPayload) -> Self {
+        self.channel.change_application_payload(payload);
+        self
+    }
+
+    /// Builder: Minimum time between two probes of this traceroute, in milliseconds
+    pub fn with_probe_interval(mut self, probe_interval: u64) -> Self {
+        self.channel.change_probe_interval(Duration::from_millis(probe_interval));
+        self
+    }
+
+    /// Builder: Maximum number of probes per second sent by all traceroutes in the process,
+    /// enforced by a shared token bucket (see [`util::pacing::set_max_pps`]). The limit is
+    /// installed when this trace sends its first probe, unless a stricter one is already in
+    /// place, and then applies to every trace. Replayed captures are not paced
+    pub fn with_max_pps(mut self, max_pps: u32) -> Self {
+        self.channel.change_max_pps(max_pps);
+        self
+    }
+
+    /// Builder: Adaptive timeout policy replacing the fixed timeout per query. Replies arriving
+    /// after the timeout are still recorded in [`TracerouteHop::late_replies`]
+    pub fn with_adaptive_timeout(mut self, adaptive_timeout: AdaptiveTimeout) -> Self {
+        self.adaptive_timeout = Some(adaptive_timeout);
+        self
+    }
+
+    /// Builder: Stop after this many consecutive hops without any answer
+    pub fn with_gap_limit(mut self, gap_limit: u32) -> Self {
+        self.gap_limit = Some(gap_limit);
+        self
+    }
+
+    /// Builder: Estimate the number of hops to the destination before tracing, and stop probing
+    /// beyond it. The trace still starts at the first TTL, as only the maximum number of hops is
+    /// lowered. Estimation probes count towards the probe budget
+    pub fn with_hop_count_estimation(mut self, hop_count_estimation: HopCountEstimation) -> Self {
+        self.hop_count_estimation = Some(hop_count_estimation);
+        self
+    }
+
+    /// Builder: Overall deadline of the trace in milliseconds, counted from the first probe.
+    /// The current hop is cut short when it passes
+    pub fn with_deadline(mut self, deadline: u64) -> Self {
+        self.deadline = Some(Duration::from_millis(deadline));
+        self
+    }
+
+    /// Builder: Maximum number of probes sent by the trace. The current hop is cut short when
+    /// the budget runs out
+    pub fn with_probe_budget(mut self, probe_budget: u32) -> Self {
+        self.probe_budget = Some(probe_budget);
+        self
+    }
+
+    /// Builder: Transport used to send probes and receive replies instead of the interface,
+    /// e.g. a [`PcapReplay`](util::transport::PcapReplay) to re-run a trace from a capture
+    pub fn with_transport(mut self, transport: Box<dyn Transport>) -> Self {
+        self.channel.change_transport(transport);
+        self
+    }
+
+    /// Builder: Writes every transmitted and received frame, with its timestamp and direction,
+    /// to `output` in pcapng format, e.g. a `std::fs::File` to open in Wireshark
+    pub fn with_capture_file<W: Write + Send + 'static>(mut self, output: W) -> Self {
+        self.channel.change_capture(Box::new(output));
+        self
+    }
+
+    /// Builder: DSCP (6 bits) marked on every probe
+    pub fn with_dscp(mut self, dscp: u8) -> Self {
+        self.channel.change_dscp(dscp);
+        self
+    }
+
+    /// Builder: ECN codepoint (2 bits) marked on every probe, e.g. 0b10 for ECT(0)
+    pub fn with_ecn(mut self, ecn: u8) -> Self {
+        self.channel.change_ecn(ecn);
+        self
+    }
+
+    /// Builder: Tunnel (GRE or IP-in-IP) wrapping every probe, to trace the underlay path towards
+    /// the tunnel endpoint. Replies quoting either the tunnel or the probe inside it are accepted
+    pub fn with_encapsulation(mut self, encapsulation: Encapsulation) -> Self {
+        self.channel.change_encapsulation(Some(encapsulation));
+        self
+    }
+
+    /// Builder: 802.1Q VLAN tag inserted into every probe. Only replies that are untagged or
+    /// carry the same tag are accepted
+    pub fn with_vlan(mut self, tag: VlanTag) -> Self {
+        self.channel.change_vlan_tags(vec![tag]);
+        self
+    }
+
+    /// Builder: 802.1ad (QinQ) tag stack inserted into every probe, with the service tag
+    /// outside of the customer tag
+    pub fn with_qinq(mut self, service_tag: VlanTag, customer_tag: VlanTag) -> Self {
+        self.channel.change_vlan_tags(vec![service_tag, customer_tag]);
+        self
+    }
+}
+
+impl TracerouteQueryResult {
+    /// Assembles the result of a probe sent through `channel`, given its response if any
+    pub(crate) fn new(channel: &util::Channel, probe: &[u8], response: Option<ProbeResponse>, rtt: Duration) -> Self {
+        TracerouteQueryResult {
+            rtt,
+            addr: response.as_ref().map_or(String::from("*"), |response| response.responder.to_string()),
+            tos: response.as_ref()
+                .and_then(|response| response.quoted_ip_header.as_ref())
+                .map(|header| TosObservation { sent: channel.tos(), quoted: header.tos }),
+            modifications: response.as_ref()
+                .map_or(Vec::new(), |response| middlebox::modifications(probe, response)),
+            application_answered: match (&response, channel.application_payload()) {
+                (Some(response @ ProbeResponse { kind: ResponseKind::Udp { .. }, .. }), Some(application_payload)) =>
+                    application_payload.recognizes(channel.application_request(), &response.payload),
+                _ => false
+            },
+            response,
+        }
+    }
+}
+
+impl TracerouteHop {
+    /// Assembles the hop at `ttl` from the results of its queries, keeping a single result per
+    /// responder. `quoted_tos` is the TOS quoted at the previous responding hop, and is updated
+    pub(crate) fn assemble(ttl: u8, results: Vec<TracerouteQueryResult>, late_replies: Vec<LateReply>,
+                           quoted_tos: &mut Option<u8>, sent_tos: u8) -> Self {
+        let rtts = results.iter().map(|result| result.response.is_some().then_some(result.rtt)).collect();
+        let mut query_results = Vec::<TracerouteQueryResult>::new();
+        for result in results {
+            if result.addr == "*" || !query_results.iter()
+                .any(|query_result| query_result.addr == result.addr) {
+                query_results.push(result)
+            }
+        }
+
+        let hop_tos = query_results.iter().find_map(|query_result| query_result.tos.map(|tos| tos.quoted));
+        let previous_tos = quoted_tos.unwrap_or(sent_tos);
+        let tos_changed = query_results.iter()
+            .filter_map(|query_result| query_result.tos)
+            .any(|tos| tos.quoted != previous_tos);
+        if hop_tos.is_some() {
+            *quoted_tos = hop_tos;
+        }
+        TracerouteHop { ttl, query_result: query_results, rtts, late_replies, tos_changed }
+    }
+
+    /// RTT statistics over all queries of this hop
+    pub fn statistics(&self) -> RttStatistics {
+        let responders = self.query_result.iter().filter(|query_result| query_result.response.is_some()).count();
+        RttStatistics::new(&self.rtts, responders)
+    }
+
+    /// Fields of the probes that were modified before reaching this hop, without duplicates
+    pub fn modified_fields(&self) -> Vec<HeaderField> {
+        let mut fields = Vec::new();
+        for modification in self.query_result.iter().flat_map(|query_result| &query_result.modifications) {
+            if !fields.contains(&modification.field) {
+                fields.push(modification.field);
+            }
+        }
+        fields
+    }
+}
+
+impl TracerouteQueryResult {
+    /// Classifies the answer to this query. `None` if the probe expired on the way or got any
+    /// other ICMP error, i.e. never made it to the destination
+    pub fn destination_response(&self) -> Option<DestinationResponse> {
+        let response = match &self.response {
+            Some(response) => response,
+            None => return Some(DestinationResponse::Silence)
+        };
+        match response.kind {
+            ResponseKind::Tcp { flags, .. } if flags & TcpFlags::RST != 0 => Some(DestinationResponse::Rst),
+            ResponseKind::Tcp { flags, .. } if flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN | TcpFlags::ACK =>
+                Some(DestinationResponse::SynAck),
+            ResponseKind::Tcp { flags, .. } => Some(DestinationResponse::OtherTcp(flags)),
+            ResponseKind::Udp { .. } => Some(DestinationResponse::Udp),
+            ResponseKind::Sctp { chunk_type, .. } => Some(DestinationResponse::Sctp(chunk_type)),
+            ResponseKind::Dccp { packet_type, .. } => Some(DestinationResponse::Dccp(packet_type)),
+            ResponseKind::Icmp { icmp_type: IcmpTypes::EchoReply, .. } => Some(DestinationResponse::EchoReply),
+            ResponseKind::Icmp { icmp_type: IcmpTypes::DestinationUnreachable, icmp_code } =>
+                Some(DestinationResponse::Unreachable(icmp_code)),
+            ResponseKind::Icmp { .. } => None
+        }
+    }
+}
+
+/// Classifies how the destination answered a trace, judging by the last hop: the first answer
+/// other than silence, or `Silence` if the destination was never reached or never answered
+pub fn classify_destination_response(hops: &[TracerouteHop]) -> DestinationResponse {
+    hops.last()
+        .into_iter()
+        .flat_map(|hop| &hop.query_result)
+        .filter_map(TracerouteQueryResult::destination_response)
+        .find(|response| *response != DestinationResponse::Silence)
+        .unwrap_or(DestinationResponse::Silence)
 }
 
 impl Iterator for Traceroute {
     type Item = TracerouteHop;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.done || self.config.channel.max_hops_reached(self.config.max_hops as u8) {
+        if self.started.is_none() {
+            self.started = Some(self.config.channel.now());
+            if let Some(hop_count_estimation) = self.config.hop_count_estimation {
+                self.estimate_hop_count(hop_count_estimation);
+            }
+        }
+        if let Some(stop_reason) = self.check_stop_conditions() {
+            self.stop_reason.get_or_insert(stop_reason);
             return None;
         }
 
         let hop = self.calculate_next_hop();
         self.done = hop.query_result.iter()
-            .filter(|ip| ip.addr == self.addr.to_string())
-            .next().is_some();
+            .any(|ip| ip.addr == self.addr.to_string());
+        if self.done {
+            self.stop_reason.get_or_insert(StopReason::DestinationReached);
+        }
+        if hop.rtts.iter().all(Option::is_none) {
+            self.unresponsive_hops += 1;
+        } else {
+            self.unresponsive_hops = 0;
+        }
         Some(hop)
     }
 }
@@ -224,48 +721,487 @@ impl Traceroute {
 			mac: MacAddr::from_str(macstr).unwrap_or(MacAddr::zero()),
             config,
             done: false,
+            quoted_tos: None,
+            started: None,
+            probes_sent: 0,
+            unresponsive_hops: 0,
+            stop_reason: None,
+            max_rtt: None,
+            hop_count: None,
         }
     }
 
-    /// Returns a vector of traceroute hops
-    pub fn perform_traceroute(&mut self) -> Vec<TracerouteHop> {
-        let mut hops = Vec::<TracerouteHop>::new();
-        for _ in 1..self.config.max_hops {
-            if self.done {
-                return hops;
-            }
-            match self.next() {
-                Some(hop) => hops.push(hop),
-                None => {}
+    /// Starts over towards `addr` through the next hop at `mac`, with the same configuration.
+    /// The deadline counts from now
+    pub(crate) fn reset(&mut self, addr: Ipv4Addr, mac: MacAddr) {
+        self.addr = addr;
+        self.mac = mac;
+        self.done = false;
+        self.quoted_tos = None;
+        self.started = Some(self.config.channel.now());
+        self.probes_sent = 0;
+        self.unresponsive_hops = 0;
+        self.stop_reason = None;
+        self.max_rtt = None;
+        self.hop_count = None;
+        self.config.channel.take_late_replies();
+    }
+
+    /// Reason why the traceroute stopped, `None` while it is still running
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    /// Estimated number of hops to the destination, `None` if no estimation was configured or the
+    /// destination didn't answer
+    pub fn hop_count(&self) -> Option<u8> {
+        self.hop_count
+    }
+
+    /// Estimates the number of hops to the destination and lowers the maximum number of hops to it
+    fn estimate_hop_count(&mut self, hop_count_estimation: HopCountEstimation) {
+        let max_ttl = self.config.max_hops.min(u8::MAX as u32) as u8;
+        let inferred = self.destination_reply_ttl(u8::MAX).map(|reply_ttl| {
+            let initial_ttl = INITIAL_TTLS.iter().copied().find(|initial_ttl| *initial_ttl >= reply_ttl).unwrap_or(u8::MAX);
+            (initial_ttl - reply_ttl).saturating_add(1)
+        });
+        self.hop_count = match hop_count_estimation {
+            HopCountEstimation::ReplyTtl => inferred,
+            HopCountEstimation::BinarySearch => self.search_hop_count(max_ttl, inferred),
+        };
+        let probed_hops = match hop_count_estimation {
+            HopCountEstimation::ReplyTtl => self.hop_count.map(|hop_count| hop_count.saturating_add(REPLY_TTL_MARGIN)),
+            HopCountEstimation::BinarySearch => self.hop_count,
+        };
+        if let Some(probed_hops) = probed_hops {
+            self.config.max_hops = self.config.max_hops.min(probed_hops as u32);
+        }
+        self.config.channel.change_ttl(self.config.ttl);
+        self.config.channel.take_late_replies();
+    }
+
+    /// Binary search for the lowest TTL at which the destination answers, starting from `hint`
+    fn search_hop_count(&mut self, max_ttl: u8, hint: Option<u8>) -> Option<u8> {
+        let (mut low, mut high) = (self.config.ttl.max(1) as u32, max_ttl as u32);
+        let mut ttl = hint.map_or((low + high) / 2, |hint| (hint as u32).clamp(low, high.max(low)));
+        let mut hop_count = None;
+        while low <= high && self.check_probe_limits().is_none() {
+            if self.destination_reply_ttl(ttl as u8).is_some() {
+                hop_count = Some(ttl as u8);
+                high = ttl - 1;
+            } else {
+                low = ttl + 1;
             }
+            ttl = (low + high) / 2;
+        }
+        hop_count
+    }
+
+    /// Sends a single probe with `ttl` and returns the remaining TTL of the reply if the
+    /// destination itself answered
+    fn destination_reply_ttl(&mut self, ttl: u8) -> Option<u8> {
+        self.config.channel.change_ttl(ttl);
+        self.get_next_query_result().response
+            .filter(|response| response.responder == self.addr)
+            .map(|response| response.reply_ttl)
+    }
+
+    /// Returns the reason to stop before probing the next hop, if any
+    fn check_stop_conditions(&self) -> Option<StopReason> {
+        if self.done {
+            Some(StopReason::DestinationReached)
+        } else if self.config.channel.max_hops_reached(self.config.max_hops as u8) {
+            Some(StopReason::MaxHops)
+        } else if self.config.gap_limit.is_some_and(|gap_limit| self.unresponsive_hops >= gap_limit) {
+            Some(StopReason::GapLimit)
+        } else {
+            self.check_probe_limits()
         }
-        return hops;
+    }
+
+    /// Returns the reason to stop before sending the next probe, if the deadline passed or the
+    /// probe budget ran out
+    fn check_probe_limits(&self) -> Option<StopReason> {
+        let deadline = self.started.zip(self.config.deadline).map(|(started, deadline)| started + deadline);
+        if deadline.is_some_and(|deadline| self.config.channel.now() >= deadline) {
+            Some(StopReason::Deadline)
+        } else if self.config.probe_budget.is_some_and(|probe_budget| self.probes_sent >= probe_budget) {
+            Some(StopReason::ProbeBudget)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a vector of traceroute hops
+    pub fn perform_traceroute(&mut self) -> Vec<TracerouteHop> {
+        self.by_ref().collect()
+    }
+
+    /// Sends a single probe with `ttl` and `protocol` and waits for its answer. `flow_id` is the
+    /// destination port of the probe, or its identifier for ICMP. The state of the trace (next
+    /// TTL, protocol, ports, probe count, highest RTT and pending late replies) is left
+    /// untouched, and the probe is not subject to the deadline or probe budget
+    pub fn probe(&mut self, ttl: u8, protocol: Protocol, flow_id: u16) -> TracerouteQueryResult {
+        self.isolated(ttl, protocol, flow_id, Self::get_next_query_result)
+    }
+
+    /// Probes the hop at `ttl` again, with the protocol, port (or ICMP identifier) and number of
+    /// queries of the trace, whose state is left untouched as with [`Traceroute::probe`]. The hop
+    /// includes every late reply received meanwhile, which may answer probes of the trace
+    pub fn probe_hop(&mut self, ttl: u8) -> TracerouteHop {
+        let protocol = self.config.channel.protocol();
+        let flow_id = self.config.channel.flow_id();
+        self.isolated(ttl, protocol, flow_id, |traceroute| {
+            let results = (0..traceroute.config.number_of_queries)
+                .map(|_| traceroute.get_next_query_result())
+                .collect();
+            let late_replies = traceroute.config.channel.take_late_replies();
+            TracerouteHop::assemble(ttl, results, late_replies, &mut None, traceroute.config.channel.tos())
+        })
+    }
+
+    /// Runs `f` on a flow of its own, then restores the flow, probe count, highest RTT and late
+    /// replies of the trace
+    fn isolated<T>(&mut self, ttl: u8, protocol: Protocol, flow_id: u16, f: impl FnOnce(&mut Self) -> T) -> T {
+        let flow_state = self.config.channel.change_flow(ttl, protocol, flow_id);
+        let (probes_sent, max_rtt) = (self.probes_sent, self.max_rtt);
+        let late_replies = self.config.channel.take_late_replies();
+        let result = f(self);
+        self.config.channel.restore_late_replies(late_replies);
+        self.probes_sent = probes_sent;
+        self.max_rtt = max_rtt;
+        self.config.channel.restore_flow(flow_state);
+        result
     }
 
     /// Get next hop on the route. Increases TTL
     fn calculate_next_hop(&mut self) -> TracerouteHop {
-        let mut query_results = Vec::<TracerouteQueryResult>::new();
+        let results = self.run_queries();
+        let late_replies = self.config.channel.take_late_replies();
+        let sent_tos = self.config.channel.tos();
+        TracerouteHop::assemble(self.config.channel.increment_ttl(), results, late_replies, &mut self.quoted_tos, sent_tos)
+    }
+
+    /// Runs the configured number of queries at the current TTL, fewer if the deadline passes or
+    /// the probe budget runs out. Doesn't increase TTL
+    fn run_queries(&mut self) -> Vec<TracerouteQueryResult> {
+        let mut results = Vec::<TracerouteQueryResult>::new();
         for _ in 0..self.config.number_of_queries {
-            let result = self.get_next_query_result();
-            if result.addr == "*" || query_results.iter()
-                .filter(|query_result| query_result.addr == result.addr)
-                .next().is_none() {
-                query_results.push(result)
+            if self.check_probe_limits().is_some() {
+                break;
             }
+            results.push(self.get_next_query_result());
         }
-        TracerouteHop { ttl: self.config.channel.increment_ttl(), query_result: query_results }
+        results
     }
 
     /// Runs a query to the destination and returns RTT and IP of the router where
     /// time-to-live-exceeded. Doesn't increase TTL
     fn get_next_query_result(&mut self) -> TracerouteQueryResult {
-        let now = std::time::SystemTime::now();
+        self.probes_sent += 1;
+        let probe = self.config.channel.send_to(self.mac, self.addr, self.config.mtu.map(usize::from));
+        let now = self.config.channel.now();
 
-        self.config.channel.send_to(self.mac, self.addr, self.config.mtu as usize);
-        let hop_ip = self.config.channel.recv_timeout(Duration::from_secs(1));
-        TracerouteQueryResult {
-            rtt: now.elapsed().unwrap_or(Duration::from_millis(0)),
-            addr: hop_ip,
+        let timeout = self.config.adaptive_timeout
+            .map_or(self.config.timeout, |adaptive_timeout| adaptive_timeout.timeout(self.max_rtt));
+        let (response, received) = self.config.channel.recv_timeout(self.addr, timeout);
+        let rtt = received.duration_since(now).unwrap_or(Duration::from_millis(0));
+        if response.is_some() {
+            self.max_rtt = Some(self.max_rtt.map_or(rtt, |max_rtt| max_rtt.max(rtt)));
+        }
+        TracerouteQueryResult::new(&self.config.channel, &probe, response, rtt)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::util::payload::ApplicationPayload;
+    use crate::util::tests::NullTransport;
+    use crate::util::transport::PcapReplay;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use pnet::packet::udp::UdpPacket;
+    use std::sync::{Arc, Mutex};
+
+    pub(crate) const PROBER: [u8; 4] = [198, 51, 100, 1];
+    pub(crate) const TARGET: [u8; 4] = [192, 0, 2, 1];
+
+    /// Configuration probing with UDP, one query per hop, on a channel that sends nowhere
+    pub(crate) fn config(max_hops: u32) -> Config {
+        let mut config = Config::from_transport(Box::new(NullTransport)).with_max_hops(max_hops).with_number_of_queries(1);
+        util::tests::count_ip_ids_from_zero(&mut config.channel);
+        config
+    }
+
+    /// IPv4 packet from `source` to `destination` carrying `payload`
+    pub(crate) fn ipv4_packet(source: [u8; 4], destination: [u8; 4], protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0x12, 0x34, 0x40, 0x00, 64, protocol, 0, 0]);
+        packet.extend_from_slice(&source);
+        packet.extend_from_slice(&destination);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Recorded exchange: a probe to the target, answered by `responder` with an ICMP error of
+    /// `icmp_type` and `code` quoting it
+    pub(crate) fn exchange(responder: [u8; 4], icmp_type: u8, code: u8) -> (Vec<u8>, Vec<u8>) {
+        exchange_to(TARGET, responder, icmp_type, code)
+    }
+
+    /// Recorded exchange like [`exchange`], with a probe to `target`
+    pub(crate) fn exchange_to(target: [u8; 4], responder: [u8; 4], icmp_type: u8, code: u8) -> (Vec<u8>, Vec<u8>) {
+        let probe = ipv4_packet(PROBER, target, 17, &[0x82, 0x9a, 0x82, 0x9a, 0, 8, 0, 0]);
+        let mut icmp = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+        icmp.extend_from_slice(&probe);
+        (probe, ipv4_packet(responder, PROBER, 1, &icmp))
+    }
+
+    /// Linux cooked capture of the probes and replies of `exchanges`, one every 100 ms
+    pub(crate) fn capture(exchanges: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        capture_with_rtts(exchanges, &vec![10; exchanges.len()])
+    }
+
+    /// Linux cooked capture of the probes of `exchanges`, one every 100 ms, each answered after
+    /// the matching number of milliseconds in `rtts`. Records are ordered by time
+    pub(crate) fn capture_with_rtts(exchanges: &[(Vec<u8>, Vec<u8>)], rtts: &[u32]) -> Vec<u8> {
+        let mut bytes = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 113, 0, 0, 0];
+        let mut record = |millis: u32, packet_type: u8, packet: &[u8]| {
+            bytes.extend_from_slice(&(millis / 1000).to_le_bytes());
+            bytes.extend_from_slice(&(millis % 1000 * 1000).to_le_bytes());
+            bytes.extend_from_slice(&(16 + packet.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(16 + packet.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&[0, packet_type, 0, 1, 0, 6, 2, 0, 0, 0, 0, 1, 0, 0, 0x08, 0x00]);
+            bytes.extend_from_slice(packet);
+        };
+        let mut records: Vec<(u32, u8, &[u8])> = Vec::new();
+        for (index, ((probe, reply), rtt)) in exchanges.iter().zip(rtts).enumerate() {
+            let millis = 1_000 + index as u32 * 100;
+            records.push((millis, 4, probe));
+            records.push((millis + rtt, 0, reply));
+        }
+        records.sort_by_key(|(millis, _, _)| *millis);
+        for (millis, packet_type, packet) in records {
+            record(millis, packet_type, packet);
         }
+        bytes
+    }
+
+    fn traceroute(max_hops: u32, exchanges: &[(Vec<u8>, Vec<u8>)]) -> Traceroute {
+        let replay = PcapReplay::from_bytes(&capture(exchanges)).unwrap();
+        Traceroute::new("192.0.2.1", "02:00:00:00:00:02", config(max_hops).with_transport(Box::new(replay)))
+    }
+
+    #[test]
+    fn stops_when_destination_reached() {
+        let mut traceroute = traceroute(5, &[exchange([10, 0, 0, 1], 11, 0), exchange(TARGET, 3, 3)]);
+        let hops = traceroute.perform_traceroute();
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[0].query_result[0].addr, "10.0.0.1");
+        assert_eq!(hops[1].query_result[0].addr, "192.0.2.1");
+        assert_eq!(hops[1].query_result[0].rtt, Duration::from_millis(10));
+        assert_eq!(traceroute.stop_reason(), Some(StopReason::DestinationReached));
+    }
+
+    #[test]
+    fn replays_are_not_paced() {
+        let replay = PcapReplay::from_bytes(&capture(&[exchange([10, 0, 0, 1], 11, 0), exchange(TARGET, 3, 3)])).unwrap();
+        let config = config(5).with_probe_interval(60_000).with_transport(Box::new(replay));
+        let started = std::time::Instant::now();
+        assert_eq!(Traceroute::new("192.0.2.1", "02:00:00:00:00:02", config).perform_traceroute().len(), 2);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn stops_at_max_hops() {
+        let routers = [[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 3], [10, 0, 0, 4]];
+        let mut traceroute = traceroute(3, &routers.map(|router| exchange(router, 11, 0)));
+        let hops = traceroute.perform_traceroute();
+        assert_eq!(hops.iter().map(|hop| hop.ttl).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(hops[2].query_result[0].addr, "10.0.0.3");
+        assert_eq!(traceroute.stop_reason(), Some(StopReason::MaxHops));
+    }
+
+    #[test]
+    fn flags_hop_rewriting_tos() {
+        let mut exchanges = [[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 3]].map(|router| exchange(router, 11, 0));
+        // Hop 2 rewrites the DSCP to CS1, and hop 3 quotes the rewritten value
+        for (_, reply) in &mut exchanges[1..] {
+            reply[29] = 0x20;
+        }
+        let hops = traceroute(3, &exchanges).perform_traceroute();
+        assert_eq!(hops.iter().map(|hop| hop.tos_changed).collect::<Vec<_>>(), [false, true, false]);
+        assert_eq!(hops[1].query_result[0].tos.map(|tos| tos.quoted), Some(0x20));
+    }
+
+    #[test]
+    fn adaptive_timeout_bounds() {
+        let rtt = Some(Duration::from_millis(100));
+        assert_eq!(AdaptiveTimeout::default().timeout(None), Duration::from_secs(3));
+        assert_eq!(AdaptiveTimeout::default().timeout(rtt), Duration::from_millis(300));
+        assert_eq!(AdaptiveTimeout::default().timeout(Some(Duration::ZERO)), Duration::from_millis(100));
+        let timeout = |floor: u64, ceiling: u64, multiplier: f64| AdaptiveTimeout {
+            floor: Duration::from_millis(floor), ceiling: Duration::from_millis(ceiling), multiplier
+        }.timeout(rtt);
+        assert_eq!(timeout(500, 200, 3.0), Duration::from_millis(200));
+        assert_eq!(timeout(10, 200, f64::INFINITY), Duration::from_millis(200));
+        assert_eq!(timeout(10, 200, f64::MAX), Duration::from_millis(200));
+        assert_eq!(timeout(10, 200, f64::NAN), Duration::from_millis(10));
+        assert_eq!(timeout(10, 200, -1.0), Duration::from_millis(10));
+    }
+
+    /// Trace over three hops, the second answering after 90 ms, with an adaptive timeout of
+    /// `multiplier` times the highest RTT
+    fn adaptive_traceroute(multiplier: f64) -> Vec<TracerouteHop> {
+        let mut exchanges = [exchange([10, 0, 0, 1], 11, 0), exchange([10, 0, 0, 2], 11, 0), exchange(TARGET, 3, 3)];
+        // The second probe, with the second IPv4 identification, is quoted
+        exchanges[1].1[32..34].copy_from_slice(&[0, 1]);
+        let replay = PcapReplay::from_bytes(&capture_with_rtts(&exchanges, &[40, 90, 10])).unwrap();
+        let config = config(3).with_adaptive_timeout(AdaptiveTimeout::new(1, 1_000, multiplier))
+            .with_transport(Box::new(replay));
+        Traceroute::new("192.0.2.1", "02:00:00:00:00:02", config).perform_traceroute()
+    }
+
+    #[test]
+    fn adaptive_timeout_follows_rtt() {
+        let hops = adaptive_traceroute(3.0);
+        assert_eq!(hops.iter().map(|hop| hop.rtts[0]).collect::<Vec<_>>(),
+                   [Some(Duration::from_millis(40)), Some(Duration::from_millis(90)), Some(Duration::from_millis(10))]);
+        assert!(hops.iter().all(|hop| hop.late_replies.is_empty()));
+
+        // Waiting for twice the 40 ms of the first hop gives up on the second one
+        let hops = adaptive_traceroute(2.0);
+        assert_eq!(hops[1].rtts, [None]);
+        assert_eq!(hops[2].late_replies.len(), 1);
+        assert_eq!(hops[2].late_replies[0].ttl, 2);
+        assert_eq!(hops[2].late_replies[0].rtt, Duration::from_millis(90));
+        assert_eq!(hops[2].late_replies[0].response.responder, Ipv4Addr::new(10, 0, 0, 2));
+    }
+
+    /// Answer of the destination to a probe, arriving with the given remaining TTL
+    fn destination_reply(reply_ttl: u8) -> (Vec<u8>, Vec<u8>) {
+        let (probe, mut reply) = exchange(TARGET, 3, 3);
+        reply[8] = reply_ttl;
+        (probe, reply)
+    }
+
+    fn estimating_traceroute(estimation: HopCountEstimation, config: Config, exchanges: &[(Vec<u8>, Vec<u8>)]) -> Traceroute {
+        let replay = PcapReplay::from_bytes(&capture(exchanges)).unwrap();
+        let config = config.with_hop_count_estimation(estimation).with_transport(Box::new(replay));
+        Traceroute::new("192.0.2.1", "02:00:00:00:00:02", config)
+    }
+
+    #[test]
+    fn infers_hop_count_from_reply_ttl() {
+        for (reply_ttl, hop_count) in [(60, 5), (120, 9), (250, 6), (64, 1)] {
+            let mut traceroute = estimating_traceroute(HopCountEstimation::ReplyTtl, config(30), &[destination_reply(reply_ttl)]);
+            traceroute.next();
+            assert_eq!(traceroute.hop_count(), Some(hop_count));
+        }
+
+        // Hops up to two past the estimate are probed, from the first TTL
+        let mut traceroute = estimating_traceroute(HopCountEstimation::ReplyTtl, config(30), &[destination_reply(60)]);
+        let hops = traceroute.perform_traceroute();
+        assert_eq!(hops.iter().map(|hop| hop.ttl).collect::<Vec<_>>(), [1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(traceroute.stop_reason(), Some(StopReason::MaxHops));
+    }
+
+    #[test]
+    fn searches_hop_count() {
+        // Inferred 5 hops: TTL 5 reaches the destination, 2 and 3 don't, 4 does
+        let mut exchanges = vec![destination_reply(60), destination_reply(60), exchange([10, 0, 0, 2], 11, 0),
+                                 exchange([10, 0, 0, 3], 11, 0), destination_reply(61)];
+        exchanges.extend([[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 3]].map(|router| exchange(router, 11, 0)));
+        exchanges.push(exchange(TARGET, 3, 3));
+        let mut traceroute = estimating_traceroute(HopCountEstimation::BinarySearch, config(30), &exchanges);
+        let hops = traceroute.perform_traceroute();
+        assert_eq!(traceroute.hop_count(), Some(4));
+        assert_eq!(hops.iter().map(|hop| hop.ttl).collect::<Vec<_>>(), [1, 2, 3, 4]);
+        assert_eq!(traceroute.stop_reason(), Some(StopReason::DestinationReached));
+        assert_eq!(traceroute.probes_sent, 9);
+    }
+
+    #[test]
+    fn hop_count_estimation_spends_probe_budget() {
+        let exchanges = [destination_reply(60), destination_reply(60), exchange([10, 0, 0, 2], 11, 0)];
+        let mut traceroute = estimating_traceroute(HopCountEstimation::BinarySearch, config(30).with_probe_budget(3), &exchanges);
+        assert!(traceroute.perform_traceroute().is_empty());
+        assert_eq!(traceroute.hop_count(), Some(5));
+        assert_eq!(traceroute.probes_sent, 3);
+        assert_eq!(traceroute.stop_reason(), Some(StopReason::ProbeBudget));
+    }
+
+    /// Transport keeping every sent frame and receiving nothing
+    #[derive(Clone, Default)]
+    struct RecordingTransport(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Transport for RecordingTransport {
+        fn send(&mut self, frame: &[u8]) {
+            self.0.lock().unwrap().push(frame.to_vec());
+        }
+
+        fn recv(&mut self, _deadline: SystemTime) -> Option<(Vec<u8>, SystemTime)> {
+            None
+        }
+    }
+
+    /// TTL and UDP destination port of the probes sent through `transport`
+    fn sent_probes(transport: &RecordingTransport) -> Vec<(u8, u16)> {
+        transport.0.lock().unwrap().iter()
+            .map(|frame| {
+                let ip = Ipv4Packet::new(&frame[14..]).unwrap();
+                (ip.get_ttl(), UdpPacket::new(&frame[34..]).unwrap().get_destination())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn probe_hop_leaves_trace_untouched() {
+        let transport = RecordingTransport::default();
+        let config = config(5).with_number_of_queries(2).with_probe_budget(2)
+            .with_udp_payload(ApplicationPayload::Ntp)
+            .with_transport(Box::new(transport.clone()));
+        let mut traceroute = Traceroute::new("192.0.2.1", "02:00:00:00:00:02", config);
+
+        let hop = traceroute.probe_hop(4);
+        assert_eq!(hop.ttl, 4);
+        assert_eq!(hop.rtts, [None, None]);
+        assert_eq!(traceroute.probes_sent, 0);
+
+        let result = traceroute.probe(3, Protocol::UDP, 40000);
+        assert!(result.response.is_none());
+        assert_eq!(traceroute.probes_sent, 0);
+
+        let hops = traceroute.perform_traceroute();
+        assert_eq!(hops.len(), 1);
+        assert_eq!(traceroute.stop_reason(), Some(StopReason::ProbeBudget));
+        assert_eq!(sent_probes(&transport), [(4, 123), (4, 123), (3, 40000), (1, 123), (1, 123)]);
+    }
+
+    /// Source address and UDP destination port of the probe sent by a one-hop trace with `config`
+    fn first_probe(config: Config, transport: &RecordingTransport) -> (Ipv4Addr, u16) {
+        Traceroute::new("192.0.2.1", "02:00:00:00:00:02", config.with_max_hops(1)).perform_traceroute();
+        let frame = &transport.0.lock().unwrap()[0];
+        (Ipv4Packet::new(&frame[14..]).unwrap().get_source(), UdpPacket::new(&frame[34..]).unwrap().get_destination())
+    }
+
+    #[test]
+    fn interface_keeps_service_port_and_transport() {
+        let source = Ipv4Addr::from(PROBER);
+        let transport = RecordingTransport::default();
+        let first = config(1).with_udp_payload(ApplicationPayload::Ntp).with_interface(util::tests::interface())
+            .with_transport(Box::new(transport.clone()));
+        assert_eq!(first_probe(first, &transport), (source, 123));
+
+        let transport = RecordingTransport::default();
+        let second = config(1).with_transport(Box::new(transport.clone())).with_interface(util::tests::interface())
+            .with_udp_payload(ApplicationPayload::Ntp);
+        assert_eq!(first_probe(second, &transport), (source, 123));
+
+        let transport = RecordingTransport::default();
+        let third = config(1).with_transport(Box::new(transport.clone())).with_udp_payload(ApplicationPayload::Ntp)
+            .with_interface(util::tests::interface());
+        assert_eq!(first_probe(third, &transport), (source, 123));
     }
 }
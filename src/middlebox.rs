@@ -0,0 +1,340 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::response::{Ipv4Header, ProbeResponse, IPV4_OPTION_RECORD_ROUTE, IPV4_OPTION_TIMESTAMP};
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ip::IpNextHeaderProtocols;
+
+/// TCP option kind carrying the maximum segment size
+const TCP_OPTION_MSS: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+/// Header field of a probe that can be rewritten on the path
+pub enum HeaderField {
+    /// IPv4 type of service (DSCP and ECN)
+    IpTos,
+    /// IPv4 total length
+    IpTotalLength,
+    /// IPv4 identification
+    IpIdentification,
+    /// IPv4 flags and fragment offset
+    IpFlags,
+    /// IPv4 TTL, which is expected to be quoted as 0 or 1 by the router where it expired
+    IpTtl,
+    /// IPv4 transport protocol
+    IpProtocol,
+    /// IPv4 source address (e.g. NAT)
+    IpSource,
+    /// IPv4 destination address
+    IpDestination,
+    /// IPv4 options
+    IpOptions,
+    /// UDP/TCP/SCTP/DCCP source port
+    SourcePort,
+    /// UDP/TCP/SCTP/DCCP destination port
+    DestinationPort,
+    /// UDP length
+    UdpLength,
+    /// UDP checksum
+    UdpChecksum,
+    /// TCP sequence number
+    TcpSequence,
+    /// TCP acknowledgement number
+    TcpAcknowledgement,
+    /// TCP data offset and reserved bits
+    TcpDataOffset,
+    /// TCP flags
+    TcpFlags,
+    /// TCP window
+    TcpWindow,
+    /// TCP checksum
+    TcpChecksum,
+    /// TCP urgent pointer
+    TcpUrgentPointer,
+    /// TCP maximum segment size option (e.g. MSS clamping)
+    TcpMss,
+    /// TCP options other than the MSS
+    TcpOptions,
+    /// SCTP verification tag
+    SctpVerificationTag,
+    /// SCTP checksum
+    SctpChecksum,
+    /// DCCP data offset
+    DccpDataOffset,
+    /// DCCP checksum
+    DccpChecksum,
+    /// ICMP checksum
+    IcmpChecksum,
+    /// ICMP echo identifier
+    IcmpIdentifier,
+    /// ICMP echo sequence number
+    IcmpSequence,
+    /// Transport payload
+    Payload,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// Field of a probe that differs between what was sent and what the responder quoted
+pub struct Modification {
+    /// Modified field
+    pub field: HeaderField,
+    /// Value of the field as sent, in network byte order
+    pub sent: Vec<u8>,
+    /// Value of the field as quoted by the responder, in network byte order
+    pub quoted: Vec<u8>,
+}
+
+/// Compares the probe `sent` (an IPv4 packet) with the packet quoted in `response` and returns
+/// the fields that were modified on the way. Transport fields are only compared if the responder
+/// quoted them completely. Responses that do not quote the probe yield no modifications.
+/// Probes sent through a tunnel are compared by their inner packet once the responder quotes it
+pub fn modifications(sent: &[u8], response: &ProbeResponse) -> Vec<Modification> {
+    let mut modifications = Vec::new();
+    let sent = match &response.quoted_ip_header {
+        Some(quoted_header) => encapsulated_packet(sent, quoted_header).unwrap_or(sent),
+        None => sent
+    };
+    let (sent_header, quoted_header) = match (Ipv4Header::new(sent), &response.quoted_ip_header) {
+        (Ok(sent_header), Some(quoted_header)) => (sent_header, quoted_header),
+        _ => return modifications
+    };
+
+    compare(&mut modifications, HeaderField::IpTos, &[sent_header.tos], &[quoted_header.tos]);
+    compare(&mut modifications, HeaderField::IpTotalLength, &sent_header.total_length.to_be_bytes(), &quoted_header.total_length.to_be_bytes());
+    compare(&mut modifications, HeaderField::IpIdentification, &sent_header.identification.to_be_bytes(), &quoted_header.identification.to_be_bytes());
+    compare(&mut modifications, HeaderField::IpFlags, &flags_and_offset(&sent_header), &flags_and_offset(quoted_header));
+    if response.icmp_type() == Some(IcmpTypes::TimeExceeded) && quoted_header.ttl > 1 {
+        modifications.push(Modification { field: HeaderField::IpTtl, sent: vec![sent_header.ttl], quoted: vec![quoted_header.ttl] });
+    }
+    compare(&mut modifications, HeaderField::IpProtocol, &[sent_header.protocol.0], &[quoted_header.protocol.0]);
+    compare(&mut modifications, HeaderField::IpSource, &sent_header.source.octets(), &quoted_header.source.octets());
+    compare(&mut modifications, HeaderField::IpDestination, &sent_header.destination.octets(), &quoted_header.destination.octets());
+    // Routers fill in Record Route and Timestamp options by design, only their layout is compared
+    if options_layout(&sent_header.options) != options_layout(&quoted_header.options) {
+        compare(&mut modifications, HeaderField::IpOptions, &sent_header.options, &quoted_header.options);
+    }
+    if sent_header.protocol != quoted_header.protocol {
+        return modifications;
+    }
+
+    // Only the part of the quote that belonged to the original packet is meaningful
+    let sent_transport = sent.get(sent_header.header_length as usize..sent_header.total_length as usize).unwrap_or_default();
+    let quoted_length = (quoted_header.total_length as usize).saturating_sub(quoted_header.header_length as usize);
+    let quoted_transport = &response.quoted_transport_header[..quoted_length.min(response.quoted_transport_header.len())];
+    let compare_range = |modifications: &mut Vec<Modification>, field: HeaderField, start: usize, end: usize| {
+        if let (Some(sent), Some(quoted)) = (sent_transport.get(start..end), quoted_transport.get(start..end)) {
+            compare(modifications, field, sent, quoted);
+        }
+    };
+
+    let header_length = match sent_header.protocol {
+        IpNextHeaderProtocols::Udp => {
+            compare_range(&mut modifications, HeaderField::SourcePort, 0, 2);
+            compare_range(&mut modifications, HeaderField::DestinationPort, 2, 4);
+            compare_range(&mut modifications, HeaderField::UdpLength, 4, 6);
+            compare_range(&mut modifications, HeaderField::UdpChecksum, 6, 8);
+            8
+        }
+        IpNextHeaderProtocols::Tcp => {
+            compare_range(&mut modifications, HeaderField::SourcePort, 0, 2);
+            compare_range(&mut modifications, HeaderField::DestinationPort, 2, 4);
+            compare_range(&mut modifications, HeaderField::TcpSequence, 4, 8);
+            compare_range(&mut modifications, HeaderField::TcpAcknowledgement, 8, 12);
+            compare_range(&mut modifications, HeaderField::TcpDataOffset, 12, 13);
+            compare_range(&mut modifications, HeaderField::TcpFlags, 13, 14);
+            compare_range(&mut modifications, HeaderField::TcpWindow, 14, 16);
+            compare_range(&mut modifications, HeaderField::TcpChecksum, 16, 18);
+            compare_range(&mut modifications, HeaderField::TcpUrgentPointer, 18, 20);
+            let header_length = sent_transport.get(12).map_or(20, |offset| (offset >> 4) as usize * 4);
+            if let (Some(sent_options), Some(quoted_options)) = (sent_transport.get(20..header_length), quoted_transport.get(20..header_length)) {
+                let (sent_mss, quoted_mss) = (tcp_mss(sent_options), tcp_mss(quoted_options));
+                compare(&mut modifications, HeaderField::TcpMss, &sent_mss, &quoted_mss);
+                if sent_mss == quoted_mss {
+                    compare(&mut modifications, HeaderField::TcpOptions, sent_options, quoted_options);
+                }
+            }
+            header_length
+        }
+        IpNextHeaderProtocols::Sctp => {
+            compare_range(&mut modifications, HeaderField::SourcePort, 0, 2);
+            compare_range(&mut modifications, HeaderField::DestinationPort, 2, 4);
+            compare_range(&mut modifications, HeaderField::SctpVerificationTag, 4, 8);
+            compare_range(&mut modifications, HeaderField::SctpChecksum, 8, 12);
+            12
+        }
+        IpNextHeaderProtocols::Dccp => {
+            compare_range(&mut modifications, HeaderField::SourcePort, 0, 2);
+            compare_range(&mut modifications, HeaderField::DestinationPort, 2, 4);
+            compare_range(&mut modifications, HeaderField::DccpDataOffset, 4, 5);
+            compare_range(&mut modifications, HeaderField::DccpChecksum, 6, 8);
+            sent_transport.get(4).map_or(12, |offset| *offset as usize * 4)
+        }
+        IpNextHeaderProtocols::Icmp => {
+            compare_range(&mut modifications, HeaderField::IcmpChecksum, 2, 4);
+            compare_range(&mut modifications, HeaderField::IcmpIdentifier, 4, 6);
+            compare_range(&mut modifications, HeaderField::IcmpSequence, 6, 8);
+            8
+        }
+        _ => 0
+    };
+    let payload_end = sent_transport.len().min(quoted_transport.len());
+    if payload_end > header_length {
+        compare_range(&mut modifications, HeaderField::Payload, header_length, payload_end);
+    }
+    modifications
+}
+
+/// Packet inside the GRE or IP-in-IP probe `sent`, if the responder quoted that rather than the
+/// outer packet
+fn encapsulated_packet<'a>(sent: &'a [u8], quoted_header: &Ipv4Header) -> Option<&'a [u8]> {
+    let sent_header = Ipv4Header::new(sent).ok()?;
+    let offset = match sent_header.protocol {
+        _ if sent_header.protocol == quoted_header.protocol => return None,
+        IpNextHeaderProtocols::Gre => sent_header.header_length as usize + 4,
+        IpNextHeaderProtocols::Ipv4 => sent_header.header_length as usize,
+        _ => return None
+    };
+    sent.get(offset..)
+}
+
+/// Types and lengths of IPv4 options, with the contents of Record Route and Timestamp options
+/// left out
+fn options_layout(mut options: &[u8]) -> Vec<u8> {
+    let mut layout = Vec::new();
+    while let Some(&option_type) = options.first() {
+        let length = match option_type {
+            0 | 1 => 1,
+            _ => options.get(1).map_or(options.len(), |length| (*length as usize).max(2).min(options.len()))
+        };
+        match option_type {
+            IPV4_OPTION_RECORD_ROUTE | IPV4_OPTION_TIMESTAMP => layout.extend_from_slice(&options[..length.min(2)]),
+            _ => layout.extend_from_slice(&options[..length])
+        }
+        options = &options[length..];
+    }
+    layout
+}
+
+/// Records a modification of `field` if its sent and quoted values differ
+fn compare(modifications: &mut Vec<Modification>, field: HeaderField, sent: &[u8], quoted: &[u8]) {
+    if sent != quoted {
+        modifications.push(Modification { field, sent: sent.to_vec(), quoted: quoted.to_vec() });
+    }
+}
+
+/// IPv4 flags and fragment offset as they appear on the wire
+fn flags_and_offset(header: &Ipv4Header) -> [u8; 2] {
+    (((header.flags as u16) << 13) | header.fragment_offset).to_be_bytes()
+}
+
+/// Value of the MSS option in a TCP options area, empty if absent
+fn tcp_mss(mut options: &[u8]) -> Vec<u8> {
+    while let Some(&kind) = options.first() {
+        match kind {
+            0 => break,
+            1 => options = &options[1..],
+            _ => {
+                let length = options.get(1).map_or(0, |length| *length as usize);
+                if length < 2 || length > options.len() {
+                    break;
+                }
+                if kind == TCP_OPTION_MSS && length == 4 {
+                    return options[2..4].to_vec();
+                }
+                options = &options[length..];
+            }
+        }
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::parse_ipv4_packet;
+    use crate::tests::{ipv4_packet, PROBER, TARGET};
+    use crate::util::packet_builder::{IpOption, PacketBuilder, Probe};
+    use crate::util::Protocol;
+    use pnet::datalink::MacAddr;
+
+    /// IPv4 packet of `probe`, sent with `protocol` from the prober to the target
+    fn sent(protocol: Protocol, probe: Probe) -> Vec<u8> {
+        PacketBuilder::new(protocol, MacAddr::zero(), PROBER.into())
+            .build(MacAddr::zero(), TARGET.into(), &probe.with_ttl(1).with_source_port(40000))[14..].to_vec()
+    }
+
+    /// Time exceeded message from a router quoting `quoted`
+    fn time_exceeded(quoted: &[u8]) -> ProbeResponse {
+        let mut icmp = vec![11, 0, 0, 0, 0, 0, 0, 0];
+        icmp.extend_from_slice(quoted);
+        parse_ipv4_packet(&ipv4_packet([10, 0, 0, 1], PROBER, 1, &icmp)).unwrap()
+    }
+
+    /// Fields modified in the quote of `sent` after `rewrite` was applied to it
+    fn modified_fields(sent: &[u8], rewrite: impl FnOnce(&mut Vec<u8>)) -> Vec<Modification> {
+        let mut quoted = sent.to_vec();
+        rewrite(&mut quoted);
+        modifications(sent, &time_exceeded(&quoted))
+    }
+
+    #[test]
+    fn unmodified_quote() {
+        let sent = sent(Protocol::UDP, Probe::default().with_payload(vec![1, 2, 3]));
+        assert_eq!(modified_fields(&sent, |_| {}), []);
+    }
+
+    #[test]
+    fn detects_rewritten_ttl_and_tos() {
+        let sent = sent(Protocol::UDP, Probe::default());
+        assert_eq!(modified_fields(&sent, |quoted| quoted[8] = 0), []);
+        assert_eq!(modified_fields(&sent, |quoted| { quoted[1] = 0x20; quoted[8] = 3; }), [
+            Modification { field: HeaderField::IpTos, sent: vec![0], quoted: vec![0x20] },
+            Modification { field: HeaderField::IpTtl, sent: vec![1], quoted: vec![3] },
+        ]);
+    }
+
+    #[test]
+    fn detects_rewritten_ports() {
+        let sent = sent(Protocol::UDP, Probe::default().with_destination_port(33434));
+        let modifications = modified_fields(&sent, |quoted| quoted[20..24].copy_from_slice(&[0x12, 0x34, 0x82, 0x9b]));
+        assert_eq!(modifications, [
+            Modification { field: HeaderField::SourcePort, sent: 40000u16.to_be_bytes().to_vec(), quoted: vec![0x12, 0x34] },
+            Modification { field: HeaderField::DestinationPort, sent: 33434u16.to_be_bytes().to_vec(), quoted: vec![0x82, 0x9b] },
+        ]);
+    }
+
+    #[test]
+    fn detects_clamped_mss() {
+        let sent = sent(Protocol::TCP, Probe::default().with_tcp_sequence(1));
+        assert_eq!(sent[40..42], [TCP_OPTION_MSS, 4]);
+        let modifications = modified_fields(&sent, |quoted| quoted[42..44].copy_from_slice(&1400u16.to_be_bytes()));
+        assert_eq!(modifications, [
+            Modification { field: HeaderField::TcpMss, sent: sent[42..44].to_vec(), quoted: 1400u16.to_be_bytes().to_vec() },
+        ]);
+    }
+
+    #[test]
+    fn compares_options_layout_only() {
+        let sent = sent(Protocol::UDP, Probe::default().with_ip_options(vec![IpOption::RecordRoute]));
+        assert_eq!(sent[20], IPV4_OPTION_RECORD_ROUTE);
+        // Routers on the path fill in the recorded addresses and advance the pointer
+        assert_eq!(modified_fields(&sent, |quoted| { quoted[22] += 4; quoted[23..27].copy_from_slice(&[10, 0, 0, 1]); }), []);
+
+        let modifications = modified_fields(&sent, |quoted| quoted[20] = 1);
+        assert_eq!(modifications.iter().map(|modification| modification.field).collect::<Vec<_>>(), [HeaderField::IpOptions]);
+        assert_eq!(modifications[0].quoted[0], 1);
+    }
+}
@@ -0,0 +1,292 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::{Config, Traceroute};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Number of recent RTTs kept per hop for percentile estimates
+const DEFAULT_WINDOW: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+/// Statistics of a single hop accumulated over the rounds of a [`Monitor`]
+pub struct HopStatistics {
+    /// Time-To-Live of the probes
+    pub ttl: u8,
+    /// Distinct nodes that answered, in order of first appearance
+    pub responders: Vec<String>,
+    /// Number of probes sent
+    pub sent: u32,
+    /// Number of probes answered
+    pub received: u32,
+    /// RTT of the latest answer
+    pub last: Option<Duration>,
+    /// Lowest RTT
+    pub best: Option<Duration>,
+    /// Highest RTT
+    pub worst: Option<Duration>,
+    mean: f64,
+    squared_deviations: f64,
+    jitter_sum: f64,
+    recent: VecDeque<Duration>,
+    window: usize,
+}
+
+impl HopStatistics {
+    /// Creates empty statistics for the hop at `ttl`, keeping `window` RTTs for percentiles
+    fn new(ttl: u8, window: usize) -> Self {
+        HopStatistics {
+            ttl, responders: Vec::new(), sent: 0, received: 0, last: None, best: None, worst: None,
+            mean: 0.0, squared_deviations: 0.0, jitter_sum: 0.0, recent: VecDeque::new(), window,
+        }
+    }
+
+    /// Records a probe and its RTT, `None` if it went unanswered
+    fn record(&mut self, responder: Option<String>, rtt: Option<Duration>) {
+        self.sent += 1;
+        if let Some(rtt) = rtt {
+            self.record_answer(responder, rtt);
+        }
+    }
+
+    /// Records the answer to a probe counted earlier, e.g. one that arrived late
+    fn record_answer(&mut self, responder: Option<String>, rtt: Duration) {
+        if let Some(responder) = responder {
+            if !self.responders.contains(&responder) {
+                self.responders.push(responder);
+            }
+        }
+        if let Some(last) = self.last {
+            self.jitter_sum += (rtt.as_secs_f64() - last.as_secs_f64()).abs();
+        }
+        // Welford's online mean and variance
+        self.received += 1;
+        let delta = rtt.as_secs_f64() - self.mean;
+        self.mean += delta / self.received as f64;
+        self.squared_deviations += delta * (rtt.as_secs_f64() - self.mean);
+
+        self.last = Some(rtt);
+        self.best = Some(self.best.map_or(rtt, |best| best.min(rtt)));
+        self.worst = Some(self.worst.map_or(rtt, |worst| worst.max(rtt)));
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(rtt);
+    }
+
+    /// Percentage of probes that went unanswered
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 { 0.0 } else { 100.0 * self.sent.saturating_sub(self.received) as f64 / self.sent as f64 }
+    }
+
+    /// Mean RTT
+    pub fn mean(&self) -> Option<Duration> {
+        (self.received > 0).then(|| Duration::from_secs_f64(self.mean))
+    }
+
+    /// Standard deviation of the RTT
+    pub fn stddev(&self) -> Option<Duration> {
+        (self.received > 0).then(|| Duration::from_secs_f64((self.squared_deviations / self.received as f64).sqrt()))
+    }
+
+    /// Mean difference between consecutive RTTs
+    pub fn jitter(&self) -> Option<Duration> {
+        (self.received > 1).then(|| Duration::from_secs_f64(self.jitter_sum / (self.received - 1) as f64))
+    }
+
+    /// Estimate of the `percentile` (0 to 100) of the RTT over the most recent answers
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut recent: Vec<Duration> = self.recent.iter().copied().collect();
+        recent.sort();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * recent.len() as f64).ceil() as usize;
+        recent.get(rank.saturating_sub(1)).copied()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// State of a [`Monitor`] after a round
+pub struct MonitorSnapshot {
+    /// Number of rounds completed
+    pub round: u32,
+    /// Statistics of every hop up to the destination (or the maximum number of hops)
+    pub hops: Vec<HopStatistics>,
+}
+
+/// Continuous, mtr-style monitoring of the path to a destination.
+///
+/// Every round sends a single probe to every hop, from the first TTL up to the destination, and
+/// updates the statistics of the hop. The path is discovered during the first round and shortened
+/// whenever the destination answers to a lower TTL. Rounds can be run one by one with
+/// [`Monitor::run_round`] and polled with [`Monitor::snapshot`], or streamed by iterating
+pub struct Monitor {
+    traceroute: Traceroute,
+    interval: Duration,
+    window: usize,
+    round: u32,
+    path_length: u8,
+    hops: Vec<HopStatistics>,
+}
+
+impl Monitor {
+    /// Creates new instance of Monitor
+    pub fn new(addrstr: &str, macstr: &str, config: Config) -> Self {
+        let path_length = config.max_hops.min(u8::MAX as u32) as u8;
+        Monitor {
+            traceroute: Traceroute::new(addrstr, macstr, config),
+            interval: Duration::from_secs(1),
+            window: DEFAULT_WINDOW,
+            round: 0,
+            path_length,
+            hops: Vec::new(),
+        }
+    }
+
+    /// Builder: Pause between the end of a round and the start of the next one when iterating.
+    /// Default: 1s
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Builder: Number of recent RTTs kept per hop for percentile estimates. Default: 100
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Probes every hop once and returns the updated statistics. Answers arriving after their
+    /// probe timed out are credited to the hop of the probe, even from an earlier round
+    pub fn run_round(&mut self) -> MonitorSnapshot {
+        let first_ttl = self.traceroute.config.ttl.max(1);
+        let mut ttl = first_ttl;
+        while ttl <= self.path_length {
+            self.traceroute.config.channel.change_ttl(ttl);
+            let result = self.traceroute.get_next_query_result();
+            let index = (ttl - first_ttl) as usize;
+            if index == self.hops.len() {
+                self.hops.push(HopStatistics::new(ttl, self.window));
+            }
+            let answered = result.response.is_some();
+            let reached = result.addr == self.traceroute.addr.to_string();
+            self.hops[index].record(answered.then_some(result.addr), answered.then_some(result.rtt));
+            if reached {
+                self.path_length = ttl;
+                self.hops.truncate(index + 1);
+                break;
+            }
+            ttl = match ttl.checked_add(1) {
+                Some(ttl) => ttl,
+                None => break
+            };
+        }
+        for late_reply in self.traceroute.config.channel.take_late_replies() {
+            let index = late_reply.ttl.checked_sub(first_ttl).map(|index| index as usize);
+            if let Some(hop) = index.and_then(|index| self.hops.get_mut(index)) {
+                hop.record_answer(Some(late_reply.response.responder.to_string()), late_reply.rtt);
+            }
+        }
+        self.round += 1;
+        self.snapshot()
+    }
+
+    /// Current statistics
+    pub fn snapshot(&self) -> MonitorSnapshot {
+        MonitorSnapshot { round: self.round, hops: self.hops.clone() }
+    }
+}
+
+impl Iterator for Monitor {
+    type Item = MonitorSnapshot;
+
+    /// Runs the next round, pausing for the interval first unless it is the first one. Never ends
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.round > 0 {
+            std::thread::sleep(self.interval);
+        }
+        Some(self.run_round())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{capture_with_rtts, config, exchange, TARGET};
+    use crate::util::transport::PcapReplay;
+
+    fn hop(window: usize, rtts: &[u64]) -> HopStatistics {
+        let mut hop = HopStatistics::new(1, window);
+        for rtt in rtts {
+            hop.record(Some("192.0.2.1".to_string()), Some(Duration::from_millis(*rtt)));
+        }
+        hop
+    }
+
+    #[test]
+    fn mean_stddev_and_jitter() {
+        let hop = hop(DEFAULT_WINDOW, &[1, 2, 3, 4]);
+        assert_eq!(hop.mean(), Some(Duration::from_micros(2_500)));
+        assert!((hop.stddev().unwrap().as_secs_f64() * 1000.0 - 1.25f64.sqrt()).abs() < 1e-6);
+        assert_eq!(hop.jitter(), Some(Duration::from_millis(1)));
+        assert_eq!(hop.best, Some(Duration::from_millis(1)));
+        assert_eq!(hop.worst, Some(Duration::from_millis(4)));
+        assert_eq!(hop.responders, vec!["192.0.2.1".to_string()]);
+    }
+
+    #[test]
+    fn percentiles_over_small_windows() {
+        let last_three = hop(3, &[1, 2, 3, 4, 5]);
+        assert_eq!(last_three.percentile(50.0), Some(Duration::from_millis(4)));
+        assert_eq!(last_three.percentile(90.0), Some(Duration::from_millis(5)));
+        assert_eq!(last_three.percentile(100.0), Some(Duration::from_millis(5)));
+        assert_eq!(last_three.percentile(0.0), Some(Duration::from_millis(3)));
+
+        let single = hop(1, &[7]);
+        assert_eq!(single.percentile(50.0), Some(Duration::from_millis(7)));
+        assert_eq!(single.percentile(100.0), Some(Duration::from_millis(7)));
+
+        assert_eq!(hop(DEFAULT_WINDOW, &[]).percentile(50.0), None);
+    }
+
+    #[test]
+    fn credits_late_replies_to_their_hop() {
+        let mut exchanges = [exchange([10, 0, 0, 1], 11, 0), exchange(TARGET, 3, 3)];
+        // The first probe, with the first IPv4 identification, is answered after the second one
+        exchanges[0].1[32..34].copy_from_slice(&[0, 0]);
+        let replay = PcapReplay::from_bytes(&capture_with_rtts(&exchanges, &[105, 10])).unwrap();
+        let mut monitor = Monitor::new("192.0.2.1", "02:00:00:00:00:02", config(5).with_transport(Box::new(replay)));
+        let snapshot = monitor.run_round();
+        assert_eq!(snapshot.hops.len(), 2);
+        assert_eq!((snapshot.hops[0].sent, snapshot.hops[0].received), (1, 1));
+        assert_eq!(snapshot.hops[0].loss(), 0.0);
+        assert_eq!(snapshot.hops[0].last, Some(Duration::from_millis(105)));
+        assert_eq!(snapshot.hops[0].responders, ["10.0.0.1"]);
+        assert_eq!(snapshot.hops[1].last, Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn every_probe_lost() {
+        let mut hop = HopStatistics::new(1, DEFAULT_WINDOW);
+        for _ in 0..3 {
+            hop.record(None, None);
+        }
+        assert_eq!(hop.sent, 3);
+        assert_eq!(hop.loss(), 100.0);
+        assert_eq!(hop.mean(), None);
+        assert_eq!(hop.stddev(), None);
+        assert_eq!(hop.jitter(), None);
+        assert_eq!(hop.percentile(50.0), None);
+    }
+}
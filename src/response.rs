@@ -0,0 +1,686 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::icmp::{IcmpCode, IcmpPacket, IcmpType, IcmpTypes};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::vlan::VlanPacket;
+use pnet::packet::Packet;
+use std::net::Ipv4Addr;
+
+/// IPv4 option type of Record Route
+pub(crate) const IPV4_OPTION_RECORD_ROUTE: u8 = 7;
+/// IPv4 option type of Internet Timestamp
+pub(crate) const IPV4_OPTION_TIMESTAMP: u8 = 68;
+/// Length of the original datagram quoted by RFC 4884 compliant routers that do not set the
+/// length field
+const RFC4884_DEFAULT_QUOTE_LENGTH: usize = 128;
+
+#[derive(Clone, Debug, PartialEq)]
+/// Response to a traceroute probe, decoded from a raw frame
+pub struct ProbeResponse {
+    /// Address of the node that sent the response
+    pub responder: Ipv4Addr,
+    /// Protocol-specific type of the response
+    pub kind: ResponseKind,
+    /// Remaining TTL of the response when it arrived
+    pub reply_ttl: u8,
+    /// Raw IPv4 options of the response itself, e.g. a Record Route echoed by the destination
+    pub reply_ip_options: Vec<u8>,
+    /// Size of the response IPv4 packet in bytes
+    pub size: usize,
+    /// VLAN identifiers the frame was tagged with, outermost first
+    pub vlan_ids: Vec<u16>,
+    /// IPv4 header of the probe as quoted by ICMP errors
+    pub quoted_ip_header: Option<Ipv4Header>,
+    /// Bytes following the quoted IPv4 header, starting with the probe's transport header
+    pub quoted_transport_header: Vec<u8>,
+    /// IPv4 header of the probe inside a quoted GRE or IP-in-IP packet, if quoted completely
+    pub encapsulated_ip_header: Option<Ipv4Header>,
+    /// ICMP extension objects (RFC 4884), such as MPLS label stacks
+    pub extensions: Vec<IcmpExtension>,
+    /// Transport payload of UDP responses, i.e. the answer of the application at the destination.
+    /// For ICMP echo replies, the echoed identifier, sequence number and data
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Protocol-specific type of a response
+pub enum ResponseKind {
+    /// ICMP message
+    Icmp {
+        /// ICMP type
+        icmp_type: IcmpType,
+        /// ICMP code
+        icmp_code: IcmpCode,
+    },
+    /// TCP segment sent by the destination in reply to a TCP probe
+    Tcp {
+        /// Source port, i.e. the destination port of the probe
+        source_port: u16,
+        /// Destination port, i.e. the source port of the probe
+        destination_port: u16,
+        /// TCP flags (see `pnet::packet::tcp::TcpFlags`)
+        flags: u8,
+        /// Acknowledgement number, i.e. the sequence number of the probe plus one for a SYN
+        acknowledgement: u32,
+    },
+    /// UDP datagram sent by the destination in reply to a UDP probe with an application payload
+    Udp {
+        /// Source port, i.e. the destination port of the probe
+        source_port: u16,
+        /// Destination port, i.e. the source port of the probe
+        destination_port: u16,
+    },
+    /// SCTP packet sent by the destination in reply to an SCTP probe
+    Sctp {
+        /// Source port, i.e. the destination port of the probe
+        source_port: u16,
+        /// Destination port, i.e. the source port of the probe
+        destination_port: u16,
+        /// Type of the first chunk, e.g. 2 for INIT-ACK or 6 for ABORT
+        chunk_type: u8,
+    },
+    /// DCCP packet sent by the destination in reply to a DCCP probe
+    Dccp {
+        /// Source port, i.e. the destination port of the probe
+        source_port: u16,
+        /// Destination port, i.e. the source port of the probe
+        destination_port: u16,
+        /// DCCP packet type, e.g. 1 for a Response or 7 for a Reset
+        packet_type: u8,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// Decoded IPv4 header
+pub struct Ipv4Header {
+    /// Header length in bytes
+    pub header_length: u8,
+    /// Type of service byte (DSCP and ECN)
+    pub tos: u8,
+    /// Total length of the packet
+    pub total_length: u16,
+    /// Identification
+    pub identification: u16,
+    /// Flags (3 bits)
+    pub flags: u8,
+    /// Fragment offset in units of 8 bytes
+    pub fragment_offset: u16,
+    /// Time-To-Live
+    pub ttl: u8,
+    /// Transport protocol
+    pub protocol: IpNextHeaderProtocol,
+    /// Header checksum
+    pub checksum: u16,
+    /// Source address
+    pub source: Ipv4Addr,
+    /// Destination address
+    pub destination: Ipv4Addr,
+    /// Raw IPv4 options
+    pub options: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// ICMP extension object (RFC 4884)
+pub struct IcmpExtension {
+    /// Class number, e.g. 1 for MPLS label stacks (RFC 4950)
+    pub class: u8,
+    /// Class sub-type
+    pub c_type: u8,
+    /// Object payload
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Entry of an MPLS label stack reported by an ICMP extension (RFC 4950)
+pub struct MplsLabel {
+    /// Label value (20 bits)
+    pub label: u32,
+    /// Traffic class (3 bits)
+    pub traffic_class: u8,
+    /// Bottom of stack flag
+    pub bottom_of_stack: bool,
+    /// TTL of the labelled packet
+    pub ttl: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Entry of an IPv4 Internet Timestamp option (RFC 791)
+pub struct IpTimestamp {
+    /// Address of the router that recorded the timestamp, if requested
+    pub address: Option<Ipv4Addr>,
+    /// Milliseconds since midnight UT, or any value with the high-order bit set if the router
+    /// has no standard time
+    pub timestamp: u32,
+}
+
+impl ProbeResponse {
+    /// Response from the sender of `header`, of the given `kind`, quoting nothing
+    fn new(header: &Ipv4Header, kind: ResponseKind) -> Self {
+        ProbeResponse {
+            responder: header.source,
+            kind,
+            reply_ttl: header.ttl,
+            reply_ip_options: header.options.clone(),
+            size: header.total_length as usize,
+            vlan_ids: Vec::new(),
+            quoted_ip_header: None,
+            quoted_transport_header: Vec::new(),
+            encapsulated_ip_header: None,
+            extensions: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    /// Checks whether this response answers a probe sent to `destination`: errors must quote a
+    /// packet to `destination`, possibly inside a tunnel, while echo replies and TCP segments
+    /// must come from it
+    pub fn answers(&self, destination: Ipv4Addr) -> bool {
+        match (&self.quoted_ip_header, self.kind) {
+            (Some(header), _) => header.destination == destination
+                || self.encapsulated_ip_header.as_ref().is_some_and(|header| header.destination == destination),
+            (None, ResponseKind::Icmp { icmp_type: IcmpTypes::EchoReply, .. })
+            | (None, ResponseKind::Tcp { .. })
+            | (None, ResponseKind::Udp { .. })
+            | (None, ResponseKind::Sctp { .. })
+            | (None, ResponseKind::Dccp { .. }) =>
+                self.responder == destination,
+            _ => false
+        }
+    }
+
+    /// ICMP type of the response, `None` for non-ICMP responses
+    pub fn icmp_type(&self) -> Option<IcmpType> {
+        match self.kind {
+            ResponseKind::Icmp { icmp_type, .. } => Some(icmp_type),
+            _ => None
+        }
+    }
+
+    /// ICMP code of the response, `None` for non-ICMP responses
+    pub fn icmp_code(&self) -> Option<IcmpCode> {
+        match self.kind {
+            ResponseKind::Icmp { icmp_code, .. } => Some(icmp_code),
+            _ => None
+        }
+    }
+
+    /// Addresses recorded by a Record Route option, from the response itself (echo replies) or
+    /// from the quoted probe. `None` if neither carries the option
+    pub fn record_route(&self) -> Option<Vec<Ipv4Addr>> {
+        record_route(&self.reply_ip_options)
+            .or_else(|| self.quoted_ip_header.as_ref().and_then(Ipv4Header::record_route))
+    }
+
+    /// Entries recorded by an Internet Timestamp option, from the response itself (echo replies)
+    /// or from the quoted probe. `None` if neither carries the option
+    pub fn timestamps(&self) -> Option<Vec<IpTimestamp>> {
+        timestamps(&self.reply_ip_options)
+            .or_else(|| self.quoted_ip_header.as_ref().and_then(Ipv4Header::timestamps))
+    }
+
+    /// MPLS label stack reported by the responder, if any
+    pub fn mpls_labels(&self) -> Vec<MplsLabel> {
+        self.extensions.iter()
+            .filter_map(|extension| extension.mpls_labels())
+            .flatten()
+            .collect()
+    }
+}
+
+impl Ipv4Header {
+    /// Decodes the IPv4 header at the start of `packet`. The header length and options are
+    /// validated, but the total length is not, since quoted headers describe a packet of which
+    /// only the first bytes are available
+    pub fn new(packet: &[u8]) -> Result<Ipv4Header, &'static str> {
+        let header = Ipv4Packet::new(packet).ok_or("truncated IPv4 header")?;
+        if header.get_version() != 4 {
+            return Err("not an IPv4 header");
+        }
+        let header_length = header.get_header_length() * 4;
+        if header_length < 20 {
+            return Err("malformed IPv4 header length");
+        }
+        let options = packet.get(20..header_length as usize).ok_or("truncated IPv4 options")?;
+        validate_ipv4_options(options)?;
+        Ok(Ipv4Header {
+            header_length,
+            tos: (header.get_dscp() << 2) | header.get_ecn(),
+            total_length: header.get_total_length(),
+            identification: header.get_identification(),
+            flags: header.get_flags(),
+            fragment_offset: header.get_fragment_offset(),
+            ttl: header.get_ttl(),
+            protocol: header.get_next_level_protocol(),
+            checksum: header.get_checksum(),
+            source: header.get_source(),
+            destination: header.get_destination(),
+            options: options.to_vec(),
+        })
+    }
+
+    /// Addresses recorded by a Record Route option, `None` if the header doesn't carry one
+    pub fn record_route(&self) -> Option<Vec<Ipv4Addr>> {
+        record_route(&self.options)
+    }
+
+    /// Entries recorded by an Internet Timestamp option, `None` if the header doesn't carry one
+    pub fn timestamps(&self) -> Option<Vec<IpTimestamp>> {
+        timestamps(&self.options)
+    }
+}
+
+/// Decodes the addresses filled in a Record Route option, up to its pointer
+fn record_route(options: &[u8]) -> Option<Vec<Ipv4Addr>> {
+    let option = find_ipv4_option(options, IPV4_OPTION_RECORD_ROUTE)?;
+    let recorded = option.get(3..)?;
+    let end = (*option.get(2)? as usize).saturating_sub(4).min(recorded.len());
+    Some(recorded[..end].chunks_exact(4)
+        .map(|address| Ipv4Addr::new(address[0], address[1], address[2], address[3]))
+        .collect())
+}
+
+/// Decodes the entries filled in an Internet Timestamp option, up to its pointer
+fn timestamps(options: &[u8]) -> Option<Vec<IpTimestamp>> {
+    let option = find_ipv4_option(options, IPV4_OPTION_TIMESTAMP)?;
+    let recorded = option.get(4..)?;
+    let end = (*option.get(2)? as usize).saturating_sub(5).min(recorded.len());
+    let with_addresses = option[3] & 0xf != 0;
+    let entry_length = if with_addresses { 8 } else { 4 };
+    Some(recorded[..end].chunks_exact(entry_length)
+        .map(|entry| {
+            let (address, timestamp) = entry.split_at(entry_length - 4);
+            IpTimestamp {
+                address: with_addresses.then(|| Ipv4Addr::new(address[0], address[1], address[2], address[3])),
+                timestamp: u32::from_be_bytes([timestamp[0], timestamp[1], timestamp[2], timestamp[3]]),
+            }
+        })
+        .collect())
+}
+
+/// Finds the option of type `option_type` in well-formed IPv4 options
+fn find_ipv4_option(mut options: &[u8], option_type: u8) -> Option<&[u8]> {
+    while let Some(&current_type) = options.first() {
+        match current_type {
+            0 => return None,
+            1 => options = &options[1..],
+            _ => {
+                let length = *options.get(1)? as usize;
+                let option = options.get(..length)?;
+                if current_type == option_type {
+                    return Some(option);
+                }
+                options = options.get(length.max(2)..)?;
+            }
+        }
+    }
+    None
+}
+
+/// Checks that IPv4 options are well-formed: every option other than End of Options List and
+/// No Operation carries a length of at least 2 that fits within the header
+fn validate_ipv4_options(mut options: &[u8]) -> Result<(), &'static str> {
+    while let Some(&option_type) = options.first() {
+        match option_type {
+            0 => return Ok(()),
+            1 => options = &options[1..],
+            _ => {
+                let length = *options.get(1).ok_or("truncated IPv4 option")? as usize;
+                if length < 2 || length > options.len() {
+                    return Err("malformed IPv4 option length");
+                }
+                options = &options[length..];
+            }
+        }
+    }
+    Ok(())
+}
+
+impl IcmpExtension {
+    /// Decodes the entries of an MPLS label stack object (class 1, c-type 1)
+    pub fn mpls_labels(&self) -> Option<Vec<MplsLabel>> {
+        if self.class != 1 || self.c_type != 1 {
+            return None;
+        }
+        Some(self.payload.chunks_exact(4).map(|entry| {
+            let value = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            MplsLabel {
+                label: value >> 12,
+                traffic_class: ((value >> 9) & 0x7) as u8,
+                bottom_of_stack: (value >> 8) & 0x1 == 1,
+                ttl: value as u8,
+            }
+        }).collect())
+    }
+}
+
+/// Decodes an Ethernet frame carrying an ICMP, TCP, UDP, SCTP or DCCP response. VLAN tags are stripped and recorded in
+/// [`ProbeResponse::vlan_ids`]
+pub fn parse_frame(frame: &[u8]) -> Result<ProbeResponse, &'static str> {
+    handle_ethernet_frame(frame)
+}
+
+/// Decodes an IPv4 packet carrying an ICMP, TCP, UDP, SCTP or DCCP response
+pub fn parse_ipv4_packet(packet: &[u8]) -> Result<ProbeResponse, &'static str> {
+    handle_ipv4_packet(packet)
+}
+
+/// Processes ICMP packets. Accepts packets that conform to the following Berkeley Packet filter
+/// formula: `icmp and (icmp[0] = 11) or (icmp[0] = 3) or (icmp[0] = 0)`, thus accepting all ICMP
+/// packets that have information about the status of probes used for traceroute.
+fn handle_icmp_packet(header: &Ipv4Header, packet: &[u8]) -> Result<ProbeResponse, &'static str> {
+    let icmp_packet = IcmpPacket::new(packet).ok_or("truncated ICMP packet")?;
+
+    let kind = ResponseKind::Icmp { icmp_type: icmp_packet.get_icmp_type(), icmp_code: icmp_packet.get_icmp_code() };
+    let mut response = ProbeResponse::new(header, kind);
+
+    match icmp_packet.get_icmp_type() {
+        IcmpTypes::TimeExceeded | IcmpTypes::DestinationUnreachable => {
+            // The 4 bytes after the checksum are unused, except for the RFC 4884 length of the
+            // original datagram in 32-bit words
+            let payload = icmp_packet.payload();
+            let quote_length = *payload.get(1).ok_or("truncated ICMP error")? as usize * 4;
+            let quote = payload.get(4..).ok_or("truncated ICMP error")?;
+            let (quote, extensions) = split_extensions(quote, quote_length);
+
+            let quoted_ip_header = Ipv4Header::new(quote)?;
+            response.quoted_transport_header = quote[quoted_ip_header.header_length as usize..].to_vec();
+            response.encapsulated_ip_header = decapsulate(quoted_ip_header.protocol, &response.quoted_transport_header);
+            response.quoted_ip_header = Some(quoted_ip_header);
+            response.extensions = extensions;
+            Ok(response)
+        }
+        IcmpTypes::EchoReply => {
+            response.payload = icmp_packet.payload().to_vec();
+            Ok(response)
+        }
+        _ => Err("wrong packet")
+    }
+}
+
+/// Processes TCP segments, which are sent by the destination in reply to TCP probes (SYN-ACK,
+/// RST...)
+fn handle_tcp_packet(header: &Ipv4Header, packet: &[u8]) -> Result<ProbeResponse, &'static str> {
+    let tcp_packet = TcpPacket::new(packet).ok_or("truncated TCP segment")?;
+
+    Ok(ProbeResponse::new(header, ResponseKind::Tcp {
+        source_port: tcp_packet.get_source(),
+        destination_port: tcp_packet.get_destination(),
+        flags: tcp_packet.get_flags(),
+        acknowledgement: tcp_packet.get_acknowledgement(),
+    }))
+}
+
+/// Processes UDP datagrams, which are sent by the application at the destination in reply to
+/// probes carrying an application payload (DNS, NTP, QUIC...)
+fn handle_udp_packet(header: &Ipv4Header, packet: &[u8]) -> Result<ProbeResponse, &'static str> {
+    let udp_packet = UdpPacket::new(packet).ok_or("truncated UDP datagram")?;
+    let length = udp_packet.get_length() as usize;
+    if length < 8 || length > packet.len() {
+        return Err("malformed UDP length");
+    }
+
+    let mut response = ProbeResponse::new(header, ResponseKind::Udp {
+        source_port: udp_packet.get_source(),
+        destination_port: udp_packet.get_destination(),
+    });
+    response.payload = packet[8..length].to_vec();
+    Ok(response)
+}
+
+/// Processes SCTP packets, which are sent by the destination in reply to INIT probes (INIT-ACK,
+/// ABORT...)
+fn handle_sctp_packet(header: &Ipv4Header, packet: &[u8]) -> Result<ProbeResponse, &'static str> {
+    if packet.len() < 13 {
+        return Err("truncated SCTP packet");
+    }
+
+    Ok(ProbeResponse::new(header, ResponseKind::Sctp {
+        source_port: u16::from_be_bytes([packet[0], packet[1]]),
+        destination_port: u16::from_be_bytes([packet[2], packet[3]]),
+        chunk_type: packet[12],
+    }))
+}
+
+/// Processes DCCP packets, which are sent by the destination in reply to DCCP-Requests
+/// (Response, Reset...)
+fn handle_dccp_packet(header: &Ipv4Header, packet: &[u8]) -> Result<ProbeResponse, &'static str> {
+    if packet.len() < 12 {
+        return Err("truncated DCCP packet");
+    }
+
+    Ok(ProbeResponse::new(header, ResponseKind::Dccp {
+        source_port: u16::from_be_bytes([packet[0], packet[1]]),
+        destination_port: u16::from_be_bytes([packet[2], packet[3]]),
+        packet_type: (packet[8] >> 1) & 0xf,
+    }))
+}
+
+/// Decodes the IPv4 header carried by a quoted GRE or IP-in-IP packet. GRE headers must
+/// announce IPv4 and may carry a checksum, key and sequence number
+fn decapsulate(protocol: IpNextHeaderProtocol, payload: &[u8]) -> Option<Ipv4Header> {
+    let offset = match protocol {
+        IpNextHeaderProtocols::Ipv4 => 0,
+        IpNextHeaderProtocols::Gre => {
+            let flags = *payload.first()?;
+            if payload.get(1)? & 0x7 != 0 || payload.get(2..4)? != EtherTypes::Ipv4.0.to_be_bytes() {
+                return None;
+            }
+            4 + [0x80, 0x20, 0x10].iter().filter(|bit| flags & **bit != 0).count() * 4
+        }
+        _ => return None
+    };
+    Ipv4Header::new(payload.get(offset..)?).ok()
+}
+
+/// Splits the payload of an ICMP error into the quoted datagram and its extension objects.
+/// `quote_length` is the RFC 4884 length field; when zero, the extension structure is looked
+/// for after the 128 byte quote used by routers predating RFC 4884
+fn split_extensions(quote: &[u8], quote_length: usize) -> (&[u8], Vec<IcmpExtension>) {
+    let offset = if quote_length > 0 { quote_length } else { RFC4884_DEFAULT_QUOTE_LENGTH };
+    let structure = match quote.get(offset..) {
+        Some(structure) if structure.len() >= 4 && structure[0] >> 4 == 2 => structure,
+        _ if quote_length > 0 => return (&quote[..quote_length.min(quote.len())], Vec::new()),
+        _ => return (quote, Vec::new())
+    };
+
+    let mut extensions = Vec::new();
+    let mut objects = &structure[4..];
+    while objects.len() >= 4 {
+        let length = u16::from_be_bytes([objects[0], objects[1]]) as usize;
+        if length < 4 || length > objects.len() {
+            break;
+        }
+        extensions.push(IcmpExtension { class: objects[2], c_type: objects[3], payload: objects[4..length].to_vec() });
+        objects = &objects[length..];
+    }
+    (&quote[..offset], extensions)
+}
+
+/// Processes IPv4 packet and passes it on to transport layer packet handler. Bytes beyond the
+/// total length (e.g. Ethernet padding) are ignored and fragments are rejected.
+fn handle_ipv4_packet(packet: &[u8]) -> Result<ProbeResponse, &'static str> {
+    let header = Ipv4Header::new(packet)?;
+    let total_length = header.total_length as usize;
+    if total_length < header.header_length as usize || total_length > packet.len() {
+        return Err("malformed IPv4 total length");
+    }
+    if header.fragment_offset != 0 || header.flags & Ipv4Flags::MoreFragments != 0 {
+        return Err("fragmented packet");
+    }
+    let payload = &packet[header.header_length as usize..total_length];
+
+    match header.protocol {
+        IpNextHeaderProtocols::Icmp => handle_icmp_packet(&header, payload),
+        IpNextHeaderProtocols::Tcp => handle_tcp_packet(&header, payload),
+        IpNextHeaderProtocols::Udp => handle_udp_packet(&header, payload),
+        IpNextHeaderProtocols::Sctp => handle_sctp_packet(&header, payload),
+        IpNextHeaderProtocols::Dccp => handle_dccp_packet(&header, payload),
+        _ => Err("wrong packet")
+    }
+}
+
+/// Processes ethernet frame and rejects all packets that are not IPv4. VLAN tags are stripped.
+fn handle_ethernet_frame(packet: &[u8]) -> Result<ProbeResponse, &'static str> {
+    let ethernet = EthernetPacket::new(packet).ok_or("truncated Ethernet frame")?;
+
+    let mut ethertype = ethernet.get_ethertype();
+    let mut payload = &packet[EthernetPacket::minimum_packet_size()..];
+    let mut vlan_ids = Vec::new();
+    while ethertype == EtherTypes::Vlan || ethertype == EtherTypes::PBridge || ethertype == EtherTypes::QinQ {
+        let vlan = VlanPacket::new(payload).ok_or("truncated VLAN tag")?;
+        vlan_ids.push(vlan.get_vlan_identifier());
+        ethertype = vlan.get_ethertype();
+        payload = &payload[VlanPacket::minimum_packet_size()..];
+    }
+
+    match ethertype {
+        EtherTypes::Ipv4 => {
+            let mut response = handle_ipv4_packet(payload)?;
+            response.vlan_ids = vlan_ids;
+            Ok(response)
+        }
+        _ => Err("wrong packet")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROBER: [u8; 4] = [10, 0, 0, 2];
+    const ROUTER: [u8; 4] = [10, 0, 0, 1];
+    const TARGET: [u8; 4] = [10, 0, 0, 9];
+
+    /// IPv4 packet with the given options (padded to 32-bit words) and payload
+    fn ipv4_packet(source: [u8; 4], destination: [u8; 4], protocol: u8, options: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut options = options.to_vec();
+        options.resize(options.len().div_ceil(4) * 4, 0);
+        let header_length = 20 + options.len();
+        let total_length = (header_length + payload.len()) as u16;
+        let mut packet = vec![0x40 | (header_length / 4) as u8, 0];
+        packet.extend_from_slice(&total_length.to_be_bytes());
+        packet.extend_from_slice(&[0x12, 0x34, 0x40, 0x00, 1, protocol, 0, 0]);
+        packet.extend_from_slice(&source);
+        packet.extend_from_slice(&destination);
+        packet.extend_from_slice(&options);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// UDP probe to the target as it would be quoted, i.e. its IPv4 header and 8 more bytes
+    fn quoted_probe(options: &[u8]) -> Vec<u8> {
+        ipv4_packet(PROBER, TARGET, 17, options, &[0xc3, 0x50, 0x82, 0x9a, 0, 10, 0, 0])
+    }
+
+    /// ICMP time exceeded from the router, with the given RFC 4884 length field and contents
+    fn time_exceeded(length: u8, contents: &[u8]) -> Vec<u8> {
+        let mut icmp = vec![11, 0, 0, 0, 0, length, 0, 0];
+        icmp.extend_from_slice(contents);
+        ipv4_packet(ROUTER, PROBER, 1, &[], &icmp)
+    }
+
+    #[test]
+    fn decodes_quoted_probe() {
+        let response = parse_ipv4_packet(&time_exceeded(0, &quoted_probe(&[]))).unwrap();
+        assert_eq!(response.responder, Ipv4Addr::from(ROUTER));
+        assert_eq!(response.icmp_type(), Some(IcmpTypes::TimeExceeded));
+        assert!(response.answers(Ipv4Addr::from(TARGET)));
+        assert_eq!(response.quoted_transport_header, [0xc3, 0x50, 0x82, 0x9a, 0, 10, 0, 0]);
+    }
+
+    #[test]
+    fn decodes_quoted_tunnel() {
+        let endpoint = [10, 0, 0, 5];
+        let inner = quoted_probe(&[]);
+        let mut gre = vec![0, 0, 0x08, 0x00];
+        gre.extend_from_slice(&inner);
+        for (protocol, payload) in [(47, gre), (4, inner.clone())] {
+            let response = parse_ipv4_packet(&time_exceeded(0, &ipv4_packet(PROBER, endpoint, protocol, &[], &payload))).unwrap();
+            assert_eq!(response.quoted_ip_header.as_ref().unwrap().destination, Ipv4Addr::from(endpoint));
+            assert_eq!(response.encapsulated_ip_header.as_ref().unwrap().destination, Ipv4Addr::from(TARGET));
+            assert!(response.answers(Ipv4Addr::from(TARGET)));
+        }
+
+        // GRE carrying something other than IPv4
+        let response = parse_ipv4_packet(&time_exceeded(0, &ipv4_packet(PROBER, endpoint, 47, &[], &[0, 0, 0x86, 0xdd]))).unwrap();
+        assert!(response.encapsulated_ip_header.is_none());
+        assert!(!response.answers(Ipv4Addr::from(TARGET)));
+    }
+
+    #[test]
+    fn rejects_truncated_quote() {
+        let quote = quoted_probe(&[]);
+        for length in 0..20 {
+            assert!(parse_ipv4_packet(&time_exceeded(0, &quote[..length])).is_err());
+        }
+        // The quoted header announces options that were cut off
+        let quote = quoted_probe(&[7, 11, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(parse_ipv4_packet(&time_exceeded(0, &quote[..24])).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_option_length() {
+        assert!(Ipv4Header::new(&quoted_probe(&[7, 1, 4, 0])).is_err());
+        assert!(Ipv4Header::new(&quoted_probe(&[7, 12, 4, 0])).is_err());
+    }
+
+    #[test]
+    fn decodes_extension_objects() {
+        let mut contents = quoted_probe(&[]);
+        contents.resize(32, 0);
+        // Extension header, then an MPLS label stack object with label 16, TTL 1
+        contents.extend_from_slice(&[0x20, 0, 0, 0, 0, 8, 1, 1, 0x00, 0x01, 0x01, 0x01]);
+        let response = parse_ipv4_packet(&time_exceeded(8, &contents)).unwrap();
+        assert_eq!(response.quoted_transport_header.len(), 12);
+        assert_eq!(response.mpls_labels(), [MplsLabel { label: 16, traffic_class: 0, bottom_of_stack: true, ttl: 1 }]);
+    }
+
+    #[test]
+    fn ignores_extension_object_overrunning_the_packet() {
+        let mut contents = quoted_probe(&[]);
+        contents.resize(32, 0);
+        contents.extend_from_slice(&[0x20, 0, 0, 0, 0x01, 0x00, 1, 1, 0x00, 0x01, 0x01, 0x01]);
+        let response = parse_ipv4_packet(&time_exceeded(8, &contents)).unwrap();
+        assert!(response.extensions.is_empty());
+        assert_eq!(response.quoted_transport_header.len(), 12);
+    }
+
+    #[test]
+    fn decodes_record_route() {
+        // Room for two addresses, one of them filled in
+        let options = [7, 11, 8, 192, 0, 2, 1, 0, 0, 0, 0];
+        let response = parse_ipv4_packet(&time_exceeded(0, &quoted_probe(&options))).unwrap();
+        assert_eq!(response.record_route(), Some(vec![Ipv4Addr::new(192, 0, 2, 1)]));
+        assert_eq!(response.timestamps(), None);
+    }
+
+    #[test]
+    fn decodes_timestamps() {
+        let only_timestamps = [68, 12, 9, 0x00, 0, 0, 0x30, 0x39, 0, 0, 0, 0];
+        let header = Ipv4Header::new(&quoted_probe(&only_timestamps)).unwrap();
+        assert_eq!(header.timestamps(), Some(vec![IpTimestamp { address: None, timestamp: 12345 }]));
+
+        let with_addresses = [68, 20, 13, 0x01, 192, 0, 2, 1, 0, 0, 0x30, 0x39, 0, 0, 0, 0, 0, 0, 0, 0];
+        let header = Ipv4Header::new(&quoted_probe(&with_addresses)).unwrap();
+        assert_eq!(header.timestamps(), Some(vec![IpTimestamp { address: Some(Ipv4Addr::new(192, 0, 2, 1)), timestamp: 12345 }]));
+        assert_eq!(header.record_route(), None);
+    }
+}
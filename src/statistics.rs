@@ -0,0 +1,148 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::TracerouteHop;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// RTT statistics over the queries of a hop. RTT fields are `None` if no query was answered
+pub struct RttStatistics {
+    /// Number of queries sent
+    pub sent: usize,
+    /// Number of queries answered
+    pub received: usize,
+    /// Number of distinct nodes that answered
+    pub responders: usize,
+    /// Lowest RTT
+    pub min: Option<Duration>,
+    /// Mean RTT
+    pub avg: Option<Duration>,
+    /// Highest RTT
+    pub max: Option<Duration>,
+    /// Median RTT
+    pub median: Option<Duration>,
+    /// Population standard deviation of the RTT
+    pub stddev: Option<Duration>,
+}
+
+impl RttStatistics {
+    /// Computes the statistics of `rtts`, one per query with `None` for queries that timed out
+    pub fn new(rtts: &[Option<Duration>], responders: usize) -> Self {
+        let mut answered: Vec<Duration> = rtts.iter().flatten().copied().collect();
+        answered.sort();
+        let received = answered.len();
+
+        let avg = (received > 0).then(|| answered.iter().sum::<Duration>() / received as u32);
+        let median = match received {
+            0 => None,
+            _ if received % 2 == 1 => Some(answered[received / 2]),
+            _ => Some((answered[received / 2 - 1] + answered[received / 2]) / 2)
+        };
+        let stddev = avg.map(|avg| {
+            let variance = answered.iter()
+                .map(|rtt| (rtt.as_secs_f64() - avg.as_secs_f64()).powi(2))
+                .sum::<f64>() / received as f64;
+            Duration::from_secs_f64(variance.sqrt())
+        });
+
+        RttStatistics {
+            sent: rtts.len(),
+            received,
+            responders,
+            min: answered.first().copied(),
+            avg,
+            max: answered.last().copied(),
+            median,
+            stddev,
+        }
+    }
+
+    /// Ratio (0 to 1) of queries that went unanswered
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 { 0.0 } else { (self.sent - self.received) as f64 / self.sent as f64 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// RTT difference between two consecutive responding hops, i.e. the latency added by the segment
+/// between them. Deltas may be negative, e.g. when a router is slow to generate ICMP errors
+pub struct SegmentDelta {
+    /// TTL of the nearer hop
+    pub from_ttl: u8,
+    /// TTL of the farther hop
+    pub to_ttl: u8,
+    /// Difference of the lowest RTTs, in milliseconds
+    pub min_delta_ms: f64,
+    /// Difference of the median RTTs, in milliseconds
+    pub median_delta_ms: f64,
+}
+
+/// RTT deltas of the segments between consecutive hops of a trace. Hops without any answer are
+/// skipped, so a segment may span several TTLs
+pub fn segment_deltas(hops: &[TracerouteHop]) -> Vec<SegmentDelta> {
+    let responding: Vec<(u8, Duration, Duration)> = hops.iter()
+        .filter_map(|hop| {
+            let statistics = hop.statistics();
+            Some((hop.ttl, statistics.min?, statistics.median?))
+        })
+        .collect();
+    responding.windows(2)
+        .map(|pair| {
+            let ((from_ttl, from_min, from_median), (to_ttl, to_min, to_median)) = (pair[0], pair[1]);
+            SegmentDelta {
+                from_ttl,
+                to_ttl,
+                min_delta_ms: (to_min.as_secs_f64() - from_min.as_secs_f64()) * 1000.0,
+                median_delta_ms: (to_median.as_secs_f64() - from_median.as_secs_f64()) * 1000.0,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(rtts: &[Option<u64>]) -> Vec<Option<Duration>> {
+        rtts.iter().map(|rtt| rtt.map(Duration::from_millis)).collect()
+    }
+
+    #[test]
+    fn statistics_of_answered_queries() {
+        let statistics = RttStatistics::new(&ms(&[Some(4), Some(1), None, Some(3), Some(2)]), 2);
+        assert_eq!(statistics.sent, 5);
+        assert_eq!(statistics.received, 4);
+        assert_eq!(statistics.responders, 2);
+        assert_eq!(statistics.min, Some(Duration::from_millis(1)));
+        assert_eq!(statistics.max, Some(Duration::from_millis(4)));
+        assert_eq!(statistics.avg, Some(Duration::from_micros(2_500)));
+        assert_eq!(statistics.median, Some(Duration::from_micros(2_500)));
+        assert!((statistics.stddev.unwrap().as_secs_f64() * 1000.0 - 1.25f64.sqrt()).abs() < 1e-6);
+        assert_eq!(statistics.loss(), 0.2);
+    }
+
+    #[test]
+    fn every_query_lost() {
+        let statistics = RttStatistics::new(&ms(&[None, None, None]), 0);
+        assert_eq!(statistics.sent, 3);
+        assert_eq!(statistics.received, 0);
+        assert_eq!(statistics.loss(), 1.0);
+        assert_eq!((statistics.min, statistics.avg, statistics.max), (None, None, None));
+        assert_eq!((statistics.median, statistics.stddev), (None, None));
+
+        assert_eq!(RttStatistics::new(&[], 0).loss(), 0.0);
+    }
+}
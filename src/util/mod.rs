@@ -16,21 +16,37 @@
 
 extern crate pnet;
 
-pub(crate) mod packet_builder;
-
+/// Construction of probe frames with configurable header fields
+pub mod packet_builder;
+pub(crate) mod pcap;
+/// Application payloads of UDP probes (DNS, NTP, QUIC)
+pub mod payload;
+/// Process-wide probe rate limiting
+pub mod pacing;
+/// Frame transports: live datalink capture and offline pcap replay
+pub mod transport;
+
+use crate::LateReply;
+use crate::response::{self, ProbeResponse, ResponseKind};
+use std::collections::VecDeque;
 use pnet::datalink::{NetworkInterface, MacAddr};
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
-use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
-use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::Packet;
-use pnet::datalink::channel;
-use std::net::{IpAddr, Ipv4Addr};
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::tcp::TcpFlags;
+use rand::Rng;
+use std::io::Write;
+use std::net::Ipv4Addr;
 use std::str::FromStr;
-use std::time::Duration;
-use async_std::task::block_on;
+use std::time::{Duration, Instant, SystemTime};
+use transport::{DatalinkTransport, Transport};
+use packet_builder::{Encapsulation, IpOption, TcpSynOptions};
+use payload::ApplicationPayload;
+
+/// Size of UDP and ICMP probe frames unless configured otherwise
+const DEFAULT_MTU: usize = 80;
+/// Number of past probes to which late replies are still attributed
+const OUTSTANDING_PROBES: usize = 64;
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 /// Protocol to be used for traceroute
 pub enum Protocol {
     /// UDP-based traceroute
@@ -38,16 +54,73 @@ pub enum Protocol {
     /// TCP-based traceroute
     TCP,
     /// ICMP-based traceroute
-    ICMP
+    ICMP,
+    /// SCTP-based traceroute, with INIT chunks
+    SCTP,
+    /// DCCP-based traceroute, with DCCP-Requests
+    DCCP
+}
+
+impl Protocol {
+    /// Checks whether probes open a connection to a fixed service port rather than incrementing
+    /// the destination port on every query
+    pub(crate) fn is_connection_oriented(self) -> bool {
+        matches!(self, Protocol::TCP | Protocol::SCTP | Protocol::DCCP)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+/// IEEE 802.1Q VLAN tag inserted into the Ethernet header of outgoing probes
+pub struct VlanTag {
+    /// VLAN identifier (12 bits)
+    pub id: u16,
+    /// Priority code point (3 bits)
+    pub priority: u8,
+}
+
+impl VlanTag {
+    /// Creates a new tag with the given VLAN identifier and priority
+    pub fn new(id: u16, priority: u8) -> Self {
+        VlanTag { id, priority }
+    }
 }
 
 pub(crate) struct Channel {
-    interface: NetworkInterface,
+    transport: Box<dyn Transport>,
+    custom_transport: bool,
+    capture: Option<pcap::Writer>,
     packet_builder: packet_builder::PacketBuilder,
+    probe: packet_builder::Probe,
+    tcp_options: TcpSynOptions,
+    application_payload: Option<ApplicationPayload>,
+    application_request: Vec<u8>,
     payload_offset: usize,
+    probe_interval: Duration,
+    last_probe: Option<Instant>,
+    max_pps: Option<u32>,
     port: u16,
     ttl: u8,
     seq: u16,
+    destination_port: u16,
+    next_ip_id: u16,
+    outstanding: VecDeque<OutstandingProbe>,
+    late_replies: Vec<LateReply>,
+}
+
+/// TTL, protocol and flow of the next probe, saved while single probes are sent
+pub(crate) struct FlowState {
+    ttl: u8,
+    protocol: Protocol,
+    port: u16,
+    seq: u16,
+    icmp_identifier: u16,
+}
+
+/// Probe that was sent earlier, identified by its IPv4 identification
+struct OutstandingProbe {
+    ip_id: u16,
+    ttl: u8,
+    sent: SystemTime,
 }
 
 impl Default for Channel {
@@ -55,8 +128,7 @@ impl Default for Channel {
         let available_interfaces = get_available_interfaces();
 
         let default_interface = available_interfaces
-            .iter()
-            .next()
+            .first()
             .expect("no interfaces available")
             .clone();
 
@@ -68,8 +140,7 @@ impl Channel {
     pub fn new(network_interface: NetworkInterface, port: u16, ttl: u8) -> Self {
         let source_ip = network_interface.ips
             .iter()
-            .filter(|i| i.is_ipv4())
-            .next()
+            .find(|i| i.is_ipv4())
             .expect("couldn't get interface IP")
             .ip()
             .to_string();
@@ -82,12 +153,36 @@ impl Channel {
             if network_interface.is_loopback() { 14 } else { 0 }
         } else { 0 };
 
+        let source_mac = network_interface.mac.unwrap();
+        let mut channel = Channel::from_transport(Box::new(DatalinkTransport::new(network_interface)), port, ttl);
+        channel.packet_builder = packet_builder::PacketBuilder::new(Protocol::UDP, source_mac, source_ip);
+        channel.payload_offset = payload_offset;
+        channel.custom_transport = false;
+        channel
+    }
+
+    /// Creates a channel sending and receiving through `transport`, without any network
+    /// interface. Probes are sent from the unspecified address
+    pub(crate) fn from_transport(transport: Box<dyn Transport>, port: u16, ttl: u8) -> Self {
         Channel {
-            interface: network_interface.clone(),
-            packet_builder: packet_builder::PacketBuilder::new(Protocol::UDP, network_interface.mac.unwrap(), source_ip),
-            payload_offset,
+            transport,
+            custom_transport: true,
+            capture: None,
+            packet_builder: packet_builder::PacketBuilder::new(Protocol::UDP, MacAddr::zero(), Ipv4Addr::UNSPECIFIED),
+            probe: Default::default(),
+            tcp_options: Default::default(),
+            application_payload: None,
+            application_request: Vec::new(),
+            payload_offset: 0,
+            probe_interval: Duration::ZERO,
+            last_probe: None,
+            max_pps: None,
             port, ttl,
-            seq: 0
+            seq: 0,
+            destination_port: port,
+            next_ip_id: rand::thread_rng().gen(),
+            outstanding: VecDeque::new(),
+            late_replies: Vec::new(),
         }
     }
 
@@ -96,6 +191,183 @@ impl Channel {
         self.packet_builder.protocol = new_protocol;
     }
 
+    /// Replace the transport used to send and receive frames. Transports always deliver
+    /// complete Ethernet frames
+    pub(crate) fn change_transport(&mut self, transport: Box<dyn Transport>) {
+        self.transport = transport;
+        self.custom_transport = true;
+        self.payload_offset = 0;
+    }
+
+    /// Move the channel to `network_interface` with `port` and `ttl`, keeping the other probe
+    /// settings applied so far. A transport set with [`Channel::change_transport`] is kept, and
+    /// probes carrying an application payload still go to its service port
+    pub(crate) fn change_interface(&mut self, network_interface: NetworkInterface, port: u16, ttl: u8) {
+        let Channel { transport, mut packet_builder, payload_offset, .. } = Channel::new(network_interface, port, ttl);
+        packet_builder.protocol = self.packet_builder.protocol;
+        packet_builder.vlan_tags = std::mem::take(&mut self.packet_builder.vlan_tags);
+        packet_builder.encapsulation = self.packet_builder.encapsulation.take();
+        if !self.custom_transport {
+            self.transport = transport;
+            self.payload_offset = payload_offset;
+        }
+        self.packet_builder = packet_builder;
+        self.change_port(self.application_payload.as_ref().map_or(port, ApplicationPayload::port));
+        self.ttl = ttl;
+    }
+
+    /// Current time as seen by the transport
+    pub(crate) fn now(&self) -> SystemTime {
+        self.transport.now()
+    }
+
+    /// Start recording every sent and received frame to `output` in pcapng format
+    pub(crate) fn change_capture(&mut self, output: Box<dyn Write + Send>) {
+        self.capture = Some(pcap::Writer::new(output));
+    }
+
+    /// Appends a frame to the capture file, if any. The capture is abandoned on the first
+    /// write error rather than interrupting the trace
+    fn record(&mut self, timestamp: SystemTime, direction: pcap::Direction, frame: &[u8]) {
+        if let Some(capture) = &mut self.capture {
+            if capture.write(timestamp, direction, frame).is_err() {
+                self.capture = None;
+            }
+        }
+    }
+
+    /// Change the DSCP marking of probes
+    pub(crate) fn change_dscp(&mut self, dscp: u8) {
+        self.probe.tos = ((dscp & 0x3f) << 2) | (self.probe.tos & 0x3);
+    }
+
+    /// Change the ECN codepoint of probes
+    pub(crate) fn change_ecn(&mut self, ecn: u8) {
+        self.probe.tos = (self.probe.tos & !0x3) | (ecn & 0x3);
+    }
+
+    /// Type of service byte of probes
+    pub(crate) fn tos(&self) -> u8 {
+        self.probe.tos
+    }
+
+    /// Change the options carried by TCP probes
+    pub(crate) fn change_tcp_options(&mut self, tcp_options: TcpSynOptions) {
+        self.tcp_options = tcp_options;
+    }
+
+    /// Change the minimum time between two probes
+    pub(crate) fn change_probe_interval(&mut self, probe_interval: Duration) {
+        self.probe_interval = probe_interval;
+    }
+
+    /// Change the process-wide rate limit, tightened when the first probe is sent
+    pub(crate) fn change_max_pps(&mut self, max_pps: u32) {
+        self.max_pps = Some(max_pps);
+    }
+
+    /// Waits until the next probe may be sent, both according to the interval between probes
+    /// and to the process-wide rate limit. Transports that aren't live, such as replays, are
+    /// never waited for
+    fn pace(&mut self) {
+        if let Some(max_pps) = self.max_pps.take() {
+            pacing::tighten_max_pps(max_pps);
+        }
+        if !self.transport.is_live() {
+            return;
+        }
+        if let Some(elapsed) = self.last_probe.map(|last_probe| last_probe.elapsed()) {
+            if elapsed < self.probe_interval {
+                std::thread::sleep(self.probe_interval - elapsed);
+            }
+        }
+        pacing::acquire();
+        self.last_probe = Some(Instant::now());
+    }
+
+    /// Change the IPv4 options of probes
+    pub(crate) fn change_ip_options(&mut self, ip_options: Vec<IpOption>) {
+        self.probe.ip_options = ip_options;
+    }
+
+    /// Change the flags of TCP probes
+    pub(crate) fn change_tcp_flags(&mut self, tcp_flags: u8) {
+        self.probe.tcp_flags = tcp_flags;
+    }
+
+    /// Change the application payload of UDP probes. Probes are then sent to the service port
+    /// without incrementing it
+    pub(crate) fn change_application_payload(&mut self, application_payload: ApplicationPayload) {
+        self.change_port(application_payload.port());
+        self.application_payload = Some(application_payload);
+    }
+
+    /// Application payload of UDP probes, if any
+    pub(crate) fn application_payload(&self) -> Option<&ApplicationPayload> {
+        self.application_payload.as_ref()
+    }
+
+    /// Application payload carried by the latest probe, empty if none
+    pub(crate) fn application_request(&self) -> &[u8] {
+        &self.application_request
+    }
+
+    /// Change the tunnel wrapping probes, if any
+    pub(crate) fn change_encapsulation(&mut self, encapsulation: Option<Encapsulation>) {
+        self.packet_builder.encapsulation = encapsulation;
+    }
+
+    /// Change VLAN tags (outermost first) of packet_builder
+    pub(crate) fn change_vlan_tags(&mut self, vlan_tags: Vec<VlanTag>) {
+        self.packet_builder.vlan_tags = vlan_tags;
+    }
+
+    /// Change the TTL of the next probe
+    pub(crate) fn change_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+    }
+
+    /// Change the destination port of the next probe, restarting the per-query increments
+    pub(crate) fn change_port(&mut self, port: u16) {
+        self.port = port;
+        self.seq = 0;
+    }
+
+    /// Change the TTL, protocol and flow of the next probes, returning the previous ones. The flow
+    /// identifier is the destination port of the first probe, or the identifier of ICMP probes
+    pub(crate) fn change_flow(&mut self, ttl: u8, protocol: Protocol, flow_id: u16) -> FlowState {
+        let previous = FlowState {
+            ttl: self.ttl,
+            protocol: self.packet_builder.protocol,
+            port: self.port,
+            seq: self.seq,
+            icmp_identifier: self.probe.icmp_identifier,
+        };
+        self.ttl = ttl;
+        self.packet_builder.protocol = protocol;
+        self.port = flow_id;
+        self.seq = 0;
+        self.probe.icmp_identifier = flow_id;
+        previous
+    }
+
+    /// Flow identifier of the next probes: their base destination port, or their identifier for ICMP
+    pub(crate) fn flow_id(&self) -> u16 {
+        match self.packet_builder.protocol {
+            Protocol::ICMP => self.probe.icmp_identifier,
+            _ => self.port
+        }
+    }
+
+    /// Restore the TTL, protocol and flow saved by [`Channel::change_flow`]
+    pub(crate) fn restore_flow(&mut self, flow_state: FlowState) {
+        self.ttl = flow_state.ttl;
+        self.packet_builder.protocol = flow_state.protocol;
+        self.port = flow_state.port;
+        self.seq = flow_state.seq;
+        self.probe.icmp_identifier = flow_state.icmp_identifier;
+    }
+
     /// Increments current TTL
     pub(crate) fn increment_ttl(&mut self) -> u8 {
         self.ttl += 1;
@@ -107,41 +379,168 @@ impl Channel {
         self.ttl > max_hops
     }
 
-    /// Sends a packet
-    pub(crate) fn send_to(&mut self, destination_mac:MacAddr, destination_ip: Ipv4Addr, mtu: usize) {
-        let (mut tx, _) = match channel(&self.interface, Default::default()) {
-            Ok(pnet::datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
-            Ok(_) => panic!("libtraceroute: unhandled util type"),
-            Err(e) => panic!("libtraceroute: unable to create util: {}", e),
-        };
-        let buf = self.packet_builder.build_packet(destination_mac, destination_ip, self.ttl, self.port + self.seq, mtu);
-        tx.send_to(&buf, None);
-        if self.packet_builder.protocol != Protocol::TCP {
-            self.seq += 1;
+    /// Sends a packet. Returns the IPv4 packet that was sent
+    /// Probes are padded to `mtu` bytes if given; otherwise UDP and ICMP probes are padded to
+    /// the traditional 80 bytes, UDP probes with an application payload carry just that, and
+    /// TCP, SCTP and DCCP probes carry no payload, like a real connection request
+    pub(crate) fn send_to(&mut self, destination_mac:MacAddr, destination_ip: Ipv4Addr, mtu: Option<usize>) -> Vec<u8> {
+        self.destination_port = self.port + self.seq;
+        let ip_id = self.next_ip_id;
+        self.next_ip_id = self.next_ip_id.wrapping_add(1);
+        let mut probe = self.probe.clone()
+            .with_ttl(self.ttl)
+            .with_ip_id(ip_id)
+            .with_icmp_sequence(ip_id)
+            .with_destination_port(self.destination_port);
+        let mut fixed_port = self.packet_builder.protocol.is_connection_oriented();
+        if let (Some(application_payload), Protocol::UDP) = (&self.application_payload, self.packet_builder.protocol) {
+            self.application_request = application_payload.build();
+            probe = probe.with_payload(self.application_request.clone());
+            fixed_port = true;
+        }
+        if self.packet_builder.protocol == Protocol::TCP {
+            // Options are only meaningful on a SYN, and a lone ACK needs a plausible acknowledgement
+            if probe.tcp_flags & TcpFlags::SYN != 0 {
+                probe = probe.with_tcp_options(self.tcp_options.to_options());
+            } else {
+                probe = probe.with_tcp_options(Vec::new());
+            }
+            if probe.tcp_flags & TcpFlags::ACK != 0 && probe.tcp_acknowledgement == 0 {
+                probe = probe.with_tcp_acknowledgement(rand::thread_rng().gen_range(1..=u32::MAX));
+            }
+        }
+        match mtu {
+            Some(mtu) => probe = probe.with_total_length(mtu - 14),
+            None if !fixed_port => probe = probe.with_total_length(DEFAULT_MTU - 14),
+            None => {}
+        }
+        let buf = self.build(destination_mac, destination_ip, &probe);
+        self.transmit(&buf);
+        if self.outstanding.len() == OUTSTANDING_PROBES {
+            self.outstanding.pop_front();
+        }
+        self.outstanding.push_back(OutstandingProbe { ip_id, ttl: self.ttl, sent: self.transport.now() });
+        if !fixed_port {
+            // Ports increase from the base port as in classic traceroute, starting over from it
+            // rather than wrapping past 65535
+            self.seq = ((self.seq as u32 + 1) % (65536 - self.port as u32)) as u16;
+        }
+        buf[self.packet_builder.link_header_length()..].to_vec()
+    }
+
+    /// Protocol of probes
+    pub(crate) fn protocol(&self) -> Protocol {
+        self.packet_builder.protocol
+    }
+
+    /// Template of probes, carrying the configured marking, options and flags
+    pub(crate) fn probe(&self) -> &packet_builder::Probe {
+        &self.probe
+    }
+
+    /// Builds the frame carrying `probe` to `destination_ip`
+    pub(crate) fn build(&self, destination_mac: MacAddr, destination_ip: Ipv4Addr, probe: &packet_builder::Probe) -> Vec<u8> {
+        self.packet_builder.build(destination_mac, destination_ip, probe)
+    }
+
+    /// Offset of the transport header of `probe` in the frames built by [`Channel::build`]
+    pub(crate) fn transport_header_offset(&self, probe: &packet_builder::Probe) -> usize {
+        self.packet_builder.transport_header_offset(probe)
+    }
+
+    /// Sends `frame` once pacing allows it, and records it to the capture file
+    pub(crate) fn transmit(&mut self, frame: &[u8]) {
+        self.pace();
+        self.transport.send(frame);
+        self.record(self.transport.now(), pcap::Direction::Outbound, frame);
+    }
+
+    /// Waits for the ICMP packet answering a probe to `destination` for specified amount of time.
+    /// Returns the decoded response, or `None` on timeout, along with the time of arrival.
+    /// Errors quoting an earlier probe are kept as late replies rather than returned
+    pub(crate) fn recv_timeout(&mut self, destination: Ipv4Addr, timeout: Duration) -> (Option<ProbeResponse>, SystemTime) {
+        let deadline = self.transport.now() + timeout;
+        while let Some((response, received)) = self.recv_until(deadline) {
+            if self.accepts(&response, destination) && !self.keep_if_late(&response, received) {
+                return (Some(response), received);
+            }
         }
+        (None, self.transport.now())
     }
 
-    /// Waits for the expected ICMP packet for specified amount of time
-    pub(crate) fn recv_timeout(&mut self, timeout: Duration) -> String {
-        let processor = async_std::task::spawn(Self::recv(self.interface.clone(), self.payload_offset));
-        let ip = block_on(async {
-            match async_std::future::timeout(timeout, processor).await {
-                Ok(ip) => ip,
-                Err(_) => String::from("*")
+    /// Waits until `deadline` for the next response to any probe that arrived with the
+    /// configured VLAN tags. Returns the decoded response along with the time of arrival
+    pub(crate) fn recv_until(&mut self, deadline: SystemTime) -> Option<(ProbeResponse, SystemTime)> {
+        while let Some((packet, received)) = self.transport.recv(deadline) {
+            self.record(received, pcap::Direction::Inbound, &packet);
+            let result = if self.payload_offset > 0 && packet.len() > self.payload_offset {
+                response::parse_ipv4_packet(&packet[self.payload_offset..])
+            } else {
+                response::parse_frame(&packet)
+            };
+            let vlan_ids: Vec<u16> = self.packet_builder.vlan_tags.iter().map(|tag| tag.id).collect();
+            match result {
+                Ok(response) if vlan_ids.ends_with(&response.vlan_ids) => return Some((response, received)),
+                _ => {}
             }
-        });
-        ip
+        }
+        None
     }
 
-    /// Waits for the expected ICMP packet to arrive until interrupted.
-    async fn recv(interface: NetworkInterface, payload_offset: usize) -> String {
-        loop {
-            match process_incoming_packet(interface.clone(), payload_offset) {
-                Ok(ip) => return ip,
-                Err(_) => {}
+    /// Records `response` as a late reply if it quotes a probe other than the latest one, or
+    /// echoes its ICMP sequence number, which is its IPv4 identification
+    fn keep_if_late(&mut self, response: &ProbeResponse, received: SystemTime) -> bool {
+        let ip_id = match (&response.quoted_ip_header, response.kind, response.payload.get(2..4)) {
+            (Some(header), _, _) => header.identification,
+            (None, ResponseKind::Icmp { icmp_type: IcmpTypes::EchoReply, .. }, Some(sequence)) =>
+                u16::from_be_bytes([sequence[0], sequence[1]]),
+            _ => return false
+        };
+        let late = self.outstanding.iter().rev().skip(1).find(|probe| probe.ip_id == ip_id);
+        match late {
+            Some(probe) => {
+                // Only as many late replies as outstanding probes are kept if nobody takes them
+                if self.late_replies.len() == OUTSTANDING_PROBES {
+                    self.late_replies.remove(0);
+                }
+                self.late_replies.push(LateReply {
+                    ttl: probe.ttl,
+                    rtt: received.duration_since(probe.sent).unwrap_or_default(),
+                    response: response.clone(),
+                });
+                true
             }
+            None => false
         }
     }
+
+    /// Late replies received since the last call
+    pub(crate) fn take_late_replies(&mut self) -> Vec<LateReply> {
+        std::mem::take(&mut self.late_replies)
+    }
+
+    /// Puts back late replies taken earlier, ahead of those received since
+    pub(crate) fn restore_late_replies(&mut self, mut late_replies: Vec<LateReply>) {
+        late_replies.append(&mut self.late_replies);
+        self.late_replies = late_replies;
+    }
+
+    fn accepts(&self, response: &ProbeResponse, destination: Ipv4Addr) -> bool {
+        let port_matches = match response.kind {
+            ResponseKind::Tcp { source_port, .. }
+            | ResponseKind::Sctp { source_port, .. }
+            | ResponseKind::Dccp { source_port, .. }
+            | ResponseKind::Udp { source_port, .. } => source_port == self.destination_port,
+            ResponseKind::Icmp { .. } => true
+        };
+        // Routers of the underlay only know the tunnel, and may not quote the probe inside it
+        let tunnel_matches = match (self.packet_builder.encapsulation, &response.quoted_ip_header) {
+            (Some(encapsulation), Some(header)) =>
+                header.destination == encapsulation.endpoint() && header.protocol == encapsulation.protocol(),
+            _ => false
+        };
+        (response.answers(destination) || tunnel_matches) && port_matches
+    }
 }
 
 /// Returns the list of interfaces that are up, not loopback, not point-to-point,
@@ -149,85 +548,230 @@ impl Channel {
 pub fn get_available_interfaces() -> Vec<NetworkInterface> {
     let all_interfaces = pnet::datalink::interfaces();
 
-    let available_interfaces: Vec<NetworkInterface>;
-
-    available_interfaces = if cfg!(target_family = "windows") {
+    if cfg!(target_family = "windows") {
         all_interfaces
             .into_iter()
             .filter(|e| e.mac.is_some()
                 && e.mac.unwrap() != MacAddr::zero()
                 && e.ips
                 .iter()
-                .filter(|ip| ip.ip().to_string() != "0.0.0.0")
-                .next().is_some())
+                .any(|ip| ip.ip().to_string() != "0.0.0.0"))
             .collect()
     } else {
         all_interfaces
             .into_iter()
             .filter(|e| e.is_up()
                 && !e.is_loopback()
-                && e.ips.iter().filter(|ip| ip.is_ipv4()).next().is_some()
+                && e.ips.iter().any(|ip| ip.is_ipv4())
                 && e.mac.is_some()
                 && e.mac.unwrap() != MacAddr::zero())
             .collect()
-    };
-
-    available_interfaces
+    }
 }
 
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use pnet::ipnetwork::IpNetwork;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use pnet::packet::tcp::TcpPacket;
+    use pnet::packet::udp::UdpPacket;
+
+    const TARGET: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    /// Transport discarding every frame and receiving nothing
+    pub(crate) struct NullTransport;
 
-// TODO: add checks for ICMP code icmp[1] = 0 and icmp[1] = 3
-/// Processes ICMP packets. Returns addresses of packets that conform to the following
-/// Berkeley Packet filter formula: `icmp and (icmp[0] = 11) or (icmp[0] = 3)`, thus
-/// accepting all ICMP packets that have information about the status of UDP packets used for
-/// traceroute.
-fn handle_icmp_packet(source: IpAddr, packet: &[u8]) -> Result<String, &'static str> {
-    let icmp_packet = IcmpPacket::new(packet).expect("malformed ICMP packet");
+    impl Transport for NullTransport {
+        fn send(&mut self, _frame: &[u8]) {}
 
-    match icmp_packet.get_icmp_type() {
-        IcmpTypes::TimeExceeded => Ok(source.to_string()),
-        IcmpTypes::EchoReply => Ok(source.to_string()),
-        IcmpTypes::DestinationUnreachable => Ok(source.to_string()),
-        _ => Err("wrong packet")
+        fn recv(&mut self, _deadline: SystemTime) -> Option<(Vec<u8>, SystemTime)> {
+            None
+        }
     }
-}
 
-/// Processes IPv4 packet and passes it on to transport layer packet handler.
-fn handle_ipv4_packet(packet: &[u8]) -> Result<String, &'static str> {
-    let header = Ipv4Packet::new(packet).expect("malformed IPv4 packet");
+    /// Transport discarding every frame and receiving the queued ones, whatever the deadline
+    struct QueueTransport(VecDeque<Vec<u8>>);
 
-    let source = IpAddr::V4(header.get_source());
-    let payload = header.payload();
+    impl Transport for QueueTransport {
+        fn send(&mut self, _frame: &[u8]) {}
 
-    match header.get_next_level_protocol() {
-        IpNextHeaderProtocols::Icmp => handle_icmp_packet(source, payload),
-        _ => Err("wrong packet")
+        fn recv(&mut self, _deadline: SystemTime) -> Option<(Vec<u8>, SystemTime)> {
+            self.0.pop_front().map(|frame| (frame, SystemTime::UNIX_EPOCH))
+        }
+    }
+
+    /// Made-up interface with address 198.51.100.1
+    pub(crate) fn interface() -> NetworkInterface {
+        NetworkInterface {
+            name: "test0".to_string(),
+            description: String::new(),
+            index: 0,
+            mac: Some(MacAddr::new(2, 0, 0, 0, 0, 1)),
+            ips: vec![IpNetwork::new(Ipv4Addr::new(198, 51, 100, 1).into(), 24).unwrap()],
+            flags: 0,
+        }
     }
-}
 
-/// Processes ethernet frame and rejects all packets that are not IPv4.
-fn handle_ethernet_frame(packet: &[u8]) -> Result<String, &'static str> {
-    let ethernet = EthernetPacket::new(packet).expect("malformed Ethernet frame");
-    match ethernet.get_ethertype() {
-        EtherTypes::Ipv4 => return handle_ipv4_packet(ethernet.payload()),
-        _ => Err("wrong packet")
+    /// Channel on a made-up interface, sending probes with IPv4 identifications counting from 0
+    pub(crate) fn channel(protocol: Protocol) -> Channel {
+        let mut channel = Channel::new(interface(), 33434, 1);
+        channel.change_transport(Box::new(NullTransport));
+        channel.change_protocol(protocol);
+        channel.next_ip_id = 0;
+        channel
     }
-}
 
-/// Start capturing packets until until expected ICMP packet was received.
-fn process_incoming_packet(interface: NetworkInterface, payload_offset: usize) -> Result<String, &'static str> {
-    let (_, mut rx) = match channel(&interface, Default::default()) {
-        Ok(pnet::datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
-        Ok(_) => panic!("libtraceroute: unhandled util type"),
-        Err(e) => panic!("libtraceroute: unable to create util: {}", e),
-    };
-    match rx.next() {
-        Ok(packet) => {
-            if payload_offset > 0 && packet.len() > payload_offset {
-                return handle_ipv4_packet(&packet[payload_offset..]);
-            }
-            return handle_ethernet_frame(packet);
+    /// Makes the IPv4 identifications of the next probes count from 0, so that they never match
+    /// the identification quoted by recorded replies
+    pub(crate) fn count_ip_ids_from_zero(channel: &mut Channel) {
+        channel.next_ip_id = 0;
+    }
+
+    fn udp_destination_port(packet: &[u8]) -> u16 {
+        let ip = Ipv4Packet::new(packet).unwrap();
+        UdpPacket::new(&packet[ip.get_header_length() as usize * 4..]).unwrap().get_destination()
+    }
+
+    fn tcp_data_offset(packet: &[u8]) -> u8 {
+        let ip = Ipv4Packet::new(packet).unwrap();
+        TcpPacket::new(&packet[ip.get_header_length() as usize * 4..]).unwrap().get_data_offset()
+    }
+
+    #[test]
+    fn syn_probe_carries_options() {
+        let mut channel = channel(Protocol::TCP);
+        let packet = channel.send_to(MacAddr::zero(), TARGET, None);
+        assert!(tcp_data_offset(&packet) > 5);
+    }
+
+    #[test]
+    fn ack_probe_carries_no_options() {
+        let mut channel = channel(Protocol::TCP);
+        channel.change_tcp_flags(TcpFlags::ACK);
+        let packet = channel.send_to(MacAddr::zero(), TARGET, None);
+        assert_eq!(tcp_data_offset(&packet), 5);
+    }
+
+    #[test]
+    fn udp_ports_increase_and_start_over() {
+        let mut channel = channel(Protocol::UDP);
+        let ports: Vec<u16> = (0..150).map(|_| udp_destination_port(&channel.send_to(MacAddr::zero(), TARGET, None))).collect();
+        assert_eq!(ports, (33434..33584).collect::<Vec<_>>());
+
+        channel.change_port(65500);
+        let ports: Vec<u16> = (0..80).map(|_| udp_destination_port(&channel.send_to(MacAddr::zero(), TARGET, None))).collect();
+        assert_eq!(ports[..2], [65500, 65501]);
+        assert_eq!(ports[35..37], [65535, 65500]);
+        assert_eq!(ports[72..74], [65500, 65501]);
+
+        channel.change_port(65535);
+        let ports: Vec<u16> = (0..3).map(|_| udp_destination_port(&channel.send_to(MacAddr::zero(), TARGET, None))).collect();
+        assert_eq!(ports, [65535; 3]);
+    }
+
+    /// Ethernet frame carrying `packet` behind VLAN tags with the given identifiers, outermost first
+    fn tagged_frame(vlan_ids: &[u16], packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        for (i, id) in vlan_ids.iter().enumerate() {
+            frame.extend_from_slice(if i == 0 && vlan_ids.len() > 1 { &[0x88, 0xa8] } else { &[0x81, 0x00] });
+            frame.extend_from_slice(&id.to_be_bytes());
+        }
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    #[test]
+    fn inserts_vlan_tag() {
+        let mut channel = channel(Protocol::UDP);
+        channel.change_vlan_tags(vec![VlanTag::new(100, 5)]);
+        let frame = channel.build(MacAddr::zero(), TARGET, channel.probe());
+        assert_eq!(frame[12..18], [0x81, 0x00, 0xa0, 100, 0x08, 0x00]);
+        assert_eq!(frame[18] >> 4, 4);
+        assert_eq!(channel.packet_builder.link_header_length(), 18);
+    }
+
+    #[test]
+    fn inserts_qinq_tags() {
+        let mut channel = channel(Protocol::UDP);
+        channel.change_vlan_tags(vec![VlanTag::new(10, 0), VlanTag::new(0x123, 1)]);
+        let frame = channel.build(MacAddr::zero(), TARGET, channel.probe());
+        assert_eq!(frame[12..22], [0x88, 0xa8, 0x00, 10, 0x81, 0x00, 0x21, 0x23, 0x08, 0x00]);
+        assert_eq!(frame[22] >> 4, 4);
+    }
+
+    #[test]
+    fn accepts_untagged_or_matching_replies() {
+        let (_, reply) = crate::tests::exchange([10, 0, 0, 1], 11, 0);
+        let replies = |channel: &mut Channel, tags: &[&[u16]]| -> Vec<Vec<u16>> {
+            let frames = tags.iter().map(|vlan_ids| tagged_frame(vlan_ids, &reply)).collect();
+            channel.change_transport(Box::new(QueueTransport(frames)));
+            std::iter::from_fn(|| channel.recv_until(SystemTime::UNIX_EPOCH)).map(|(response, _)| response.vlan_ids).collect()
+        };
+
+        let mut channel = channel(Protocol::UDP);
+        assert_eq!(replies(&mut channel, &[&[], &[100]]), [vec![]]);
+
+        channel.change_vlan_tags(vec![VlanTag::new(100, 0)]);
+        assert_eq!(replies(&mut channel, &[&[], &[200], &[100], &[10, 100]]), [vec![], vec![100]]);
+
+        channel.change_vlan_tags(vec![VlanTag::new(10, 0), VlanTag::new(100, 0)]);
+        assert_eq!(replies(&mut channel, &[&[10], &[100], &[10, 100], &[20, 100]]), [vec![100], vec![10, 100]]);
+    }
+
+    #[test]
+    fn correlates_error_quoting_tunnel() {
+        let endpoint = Ipv4Addr::new(203, 0, 113, 1);
+        let mut channel = channel(Protocol::UDP);
+        channel.change_encapsulation(Some(Encapsulation::Gre(endpoint)));
+        let packet = channel.send_to(MacAddr::zero(), TARGET, None);
+        assert_eq!(packet[16..20], endpoint.octets());
+
+        // An underlay router quoting only the outer header and the GRE header
+        let mut icmp = vec![11, 0, 0, 0, 0, 0, 0, 0];
+        icmp.extend_from_slice(&packet[..28]);
+        let reply = tagged_frame(&[], &crate::tests::ipv4_packet([10, 0, 0, 1], crate::tests::PROBER, 1, &icmp));
+        channel.change_transport(Box::new(QueueTransport(VecDeque::from([reply.clone()]))));
+        let (response, _) = channel.recv_timeout(TARGET, Duration::ZERO);
+        assert_eq!(response.unwrap().responder, Ipv4Addr::new(10, 0, 0, 1));
+
+        channel.change_encapsulation(None);
+        channel.change_transport(Box::new(QueueTransport(VecDeque::from([reply]))));
+        assert!(channel.recv_timeout(TARGET, Duration::ZERO).0.is_none());
+    }
+
+    #[test]
+    fn echo_reply_to_earlier_probe_is_late() {
+        let mut channel = channel(Protocol::ICMP);
+        let sequences: Vec<u16> = (1..=2).map(|ttl| {
+            channel.change_ttl(ttl);
+            let packet = channel.send_to(MacAddr::zero(), TARGET, None);
+            u16::from_be_bytes([packet[26], packet[27]])
+        }).collect();
+        assert_eq!(sequences, [0, 1]);
+
+        let echo_reply = |sequence: u16| {
+            let mut icmp = vec![0, 0, 0, 0, 0, 0];
+            icmp.extend_from_slice(&sequence.to_be_bytes());
+            tagged_frame(&[], &crate::tests::ipv4_packet(TARGET.octets(), crate::tests::PROBER, 1, &icmp))
+        };
+        channel.change_transport(Box::new(QueueTransport(VecDeque::from([echo_reply(0), echo_reply(1)]))));
+        let (response, _) = channel.recv_timeout(TARGET, Duration::ZERO);
+        assert_eq!(response.unwrap().payload[2..4], [0, 1]);
+        let late_replies = channel.take_late_replies();
+        assert_eq!(late_replies.len(), 1);
+        assert_eq!(late_replies[0].ttl, 1);
+
+        // Late replies nobody takes are capped, the oldest going first
+        for _ in 0..OUTSTANDING_PROBES {
+            channel.send_to(MacAddr::zero(), TARGET, None);
         }
-        Err(e) => panic!("libtraceroute: unable to receive packet: {}", e),
+        let replies = (0..2).flat_map(|_| 2..OUTSTANDING_PROBES as u16).map(echo_reply).collect();
+        channel.change_transport(Box::new(QueueTransport(replies)));
+        assert!(channel.recv_timeout(TARGET, Duration::ZERO).0.is_none());
+        let late_replies = channel.take_late_replies();
+        assert_eq!(late_replies.len(), OUTSTANDING_PROBES);
+        assert_eq!(late_replies.last().unwrap().response.payload[2..4], [0, OUTSTANDING_PROBES as u8 - 1]);
     }
 }
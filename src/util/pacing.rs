@@ -0,0 +1,124 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket shared by every traceroute in the process
+static GLOBAL_BUCKET: Mutex<Option<TokenBucket>> = Mutex::new(None);
+
+/// Token bucket allowing `rate` probes per second, with bursts of up to `capacity` probes
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32) -> Self {
+        let capacity = burst.max(1) as f64;
+        TokenBucket { rate: rate as f64, capacity, tokens: capacity, refilled: Instant::now() }
+    }
+
+    /// Takes a token if available. Otherwise returns how long to wait for one
+    fn take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * self.rate).min(self.capacity);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+/// Limits the probes sent by all traceroutes in the process to `max_pps` per second, allowing
+/// bursts of `burst` probes. `None` (the default) lifts the limit. The latest call wins
+pub fn set_max_pps(max_pps: Option<u32>, burst: u32) {
+    let mut bucket = GLOBAL_BUCKET.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *bucket = max_pps.filter(|max_pps| *max_pps > 0).map(|max_pps| TokenBucket::new(max_pps, burst));
+}
+
+/// Limits the probes sent by all traceroutes in the process to at most `max_pps` per second.
+/// A stricter limit already in place is kept, along with its tokens
+pub(crate) fn tighten_max_pps(max_pps: u32) {
+    let mut bucket = GLOBAL_BUCKET.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    tighten(&mut bucket, max_pps);
+}
+
+/// Replaces `bucket` with one allowing `max_pps` probes per second, unless it is stricter
+fn tighten(bucket: &mut Option<TokenBucket>, max_pps: u32) {
+    if max_pps > 0 && !bucket.as_ref().is_some_and(|bucket| bucket.rate <= max_pps as f64) {
+        *bucket = Some(TokenBucket::new(max_pps, 1));
+    }
+}
+
+/// Blocks until the global limit allows another probe to be sent
+pub(crate) fn acquire() {
+    loop {
+        let wait = {
+            let mut bucket = GLOBAL_BUCKET.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            match bucket.as_mut().map(TokenBucket::take) {
+                None | Some(Ok(())) => return,
+                Some(Err(wait)) => wait
+            }
+        };
+        std::thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_bursts_then_waits() {
+        let mut bucket = TokenBucket::new(10, 3);
+        assert_eq!((0..3).map(|_| bucket.take()).collect::<Vec<_>>(), [Ok(()); 3]);
+        let wait = bucket.take().unwrap_err();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_capacity() {
+        let mut bucket = TokenBucket::new(10, 2);
+        bucket.tokens = 0.0;
+        bucket.refilled -= Duration::from_millis(150);
+        assert_eq!(bucket.take(), Ok(()));
+        assert!(bucket.take().is_err());
+
+        bucket.refilled -= Duration::from_secs(10);
+        assert_eq!(bucket.take(), Ok(()));
+        assert_eq!(bucket.take(), Ok(()));
+        assert!(bucket.take().is_err());
+    }
+
+    #[test]
+    fn only_stricter_limits_replace_the_bucket() {
+        let mut bucket = None;
+        tighten(&mut bucket, 0);
+        assert!(bucket.is_none());
+        tighten(&mut bucket, 100);
+        bucket.as_mut().unwrap().tokens = 0.0;
+        tighten(&mut bucket, 200);
+        assert_eq!((bucket.as_ref().unwrap().rate, bucket.as_ref().unwrap().tokens), (100.0, 0.0));
+        tighten(&mut bucket, 50);
+        assert_eq!((bucket.as_ref().unwrap().rate, bucket.as_ref().unwrap().tokens), (50.0, 1.0));
+    }
+}
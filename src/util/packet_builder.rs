@@ -1,133 +1,824 @@
-use crate::util::Protocol;
+use crate::response::{IPV4_OPTION_RECORD_ROUTE, IPV4_OPTION_TIMESTAMP};
+use crate::util::{Protocol, VlanTag};
 use rand::Rng;
 use pnet::datalink::MacAddr;
-use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
-use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ethernet::{EtherType, EtherTypes, MutableEthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4::{MutableIpv4Packet, Ipv4Flags};
 use pnet::packet::udp::MutableUdpPacket;
-use pnet::packet::tcp::{MutableTcpPacket, TcpFlags};
-use pnet::packet::icmp::{IcmpTypes, IcmpCode, MutableIcmpPacket};
+use pnet::packet::tcp::{MutableTcpPacket, TcpFlags, TcpOption, TcpOptionPacket};
+use pnet::packet::icmp::{IcmpTypes, IcmpCode, IcmpPacket};
+use pnet::packet::icmp::echo_request::MutableEchoRequestPacket;
+use pnet::packet::vlan::{ClassOfService, MutableVlanPacket};
 use pnet::packet::MutablePacket;
 use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Length of an untagged Ethernet header
+const ETHERNET_HEADER_LENGTH: usize = 14;
+/// Length of a single 802.1Q tag
+const VLAN_TAG_LENGTH: usize = 4;
+/// Length of an IPv4 header without options
+const IPV4_HEADER_LENGTH: usize = 20;
+/// Length of a UDP header
+const UDP_HEADER_LENGTH: usize = 8;
+/// Length of a TCP header without options
+const TCP_HEADER_LENGTH: usize = 20;
+/// Length of an ICMP echo request header
+const ICMP_ECHO_HEADER_LENGTH: usize = 8;
+/// Window advertised by TCP probes, as in a Linux SYN
+const TCP_WINDOW: u16 = 64240;
+/// Length of the SCTP common header
+const SCTP_COMMON_HEADER_LENGTH: usize = 12;
+/// Length of an SCTP INIT chunk without parameters
+const SCTP_INIT_CHUNK_LENGTH: usize = 20;
+/// Length of a DCCP-Request: generic header with extended sequence number and service code
+const DCCP_REQUEST_HEADER_LENGTH: usize = 20;
+/// SCTP chunk type of INIT
+const SCTP_CHUNK_INIT: u8 = 1;
+/// SCTP Padding parameter (RFC 4820), carrying the payload and padding of INIT probes
+const SCTP_PARAMETER_PADDING: u16 = 0x8005;
+/// DCCP packet type of a Request
+const DCCP_TYPE_REQUEST: u8 = 0;
+/// Length of a GRE header without checksum, key or sequence number
+const GRE_HEADER_LENGTH: usize = 4;
+/// TTL of probes inside a tunnel, high enough to reach the destination once decapsulated
+const ENCAPSULATED_TTL: u8 = 64;
+/// Maximum length of IPv4 options
+const MAX_IPV4_OPTIONS_LENGTH: usize = 40;
+
+/// Builds probe frames from the link layer up
 pub struct PacketBuilder {
     pub(crate) protocol: Protocol,
+    pub(crate) vlan_tags: Vec<VlanTag>,
+    pub(crate) encapsulation: Option<Encapsulation>,
     source_mac: MacAddr,
     source_ip: Ipv4Addr,
 }
 
+#[derive(Clone, Debug)]
+/// Header fields and payload of a single probe. Fields that do not apply to the protocol of the
+/// [`PacketBuilder`] are ignored
+pub struct Probe {
+    pub(crate) ttl: u8,
+    pub(crate) ip_id: u16,
+    pub(crate) ip_flags: u8,
+    pub(crate) tos: u8,
+    pub(crate) ip_options: Vec<IpOption>,
+    pub(crate) source_port: Option<u16>,
+    pub(crate) destination_port: u16,
+    pub(crate) tcp_sequence: Option<u32>,
+    pub(crate) tcp_acknowledgement: u32,
+    pub(crate) tcp_window: u16,
+    pub(crate) tcp_flags: u8,
+    pub(crate) tcp_options: Vec<TcpOption>,
+    pub(crate) icmp_identifier: u16,
+    pub(crate) icmp_sequence: u16,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) total_length: Option<usize>,
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Probe {
+            ttl: 64, ip_id: 0, ip_flags: Ipv4Flags::DontFragment, tos: 0, ip_options: Vec::new(),
+            source_port: None, destination_port: 33434,
+            tcp_sequence: None, tcp_acknowledgement: 0, tcp_window: TCP_WINDOW, tcp_flags: TcpFlags::SYN,
+            tcp_options: TcpSynOptions::default().to_options(),
+            icmp_identifier: 0, icmp_sequence: 0,
+            payload: Vec::new(), total_length: None,
+        }
+    }
+}
+
+impl Probe {
+    /// Builder: IPv4 Time-To-Live
+    pub fn with_ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Builder: IPv4 identification
+    pub fn with_ip_id(mut self, ip_id: u16) -> Self {
+        self.ip_id = ip_id;
+        self
+    }
+
+    /// Builder: IPv4 flags (see `pnet::packet::ipv4::Ipv4Flags`). Default: Don't Fragment
+    pub fn with_ip_flags(mut self, ip_flags: u8) -> Self {
+        self.ip_flags = ip_flags;
+        self
+    }
+
+    /// Builder: IPv4 type of service byte (DSCP and ECN)
+    pub fn with_tos(mut self, tos: u8) -> Self {
+        self.tos = tos;
+        self
+    }
+
+    /// Builder: IPv4 options. The 40 bytes available are shared equally between them, and every
+    /// option gets as many slots as fit in its share
+    pub fn with_ip_options(mut self, ip_options: Vec<IpOption>) -> Self {
+        self.ip_options = ip_options;
+        self
+    }
+
+    /// Builder: UDP/TCP source port. Default: random port from the dynamic range
+    pub fn with_source_port(mut self, source_port: u16) -> Self {
+        self.source_port = Some(source_port);
+        self
+    }
+
+    /// Builder: UDP/TCP destination port
+    pub fn with_destination_port(mut self, destination_port: u16) -> Self {
+        self.destination_port = destination_port;
+        self
+    }
+
+    /// Builder: TCP sequence number. Default: random initial sequence number
+    pub fn with_tcp_sequence(mut self, tcp_sequence: u32) -> Self {
+        self.tcp_sequence = Some(tcp_sequence);
+        self
+    }
+
+    /// Builder: TCP acknowledgement number
+    pub fn with_tcp_acknowledgement(mut self, tcp_acknowledgement: u32) -> Self {
+        self.tcp_acknowledgement = tcp_acknowledgement;
+        self
+    }
+
+    /// Builder: TCP window size. Default: 64240
+    pub fn with_tcp_window(mut self, tcp_window: u16) -> Self {
+        self.tcp_window = tcp_window;
+        self
+    }
+
+    /// Builder: TCP flags (see `pnet::packet::tcp::TcpFlags`). Default: SYN
+    pub fn with_tcp_flags(mut self, tcp_flags: u8) -> Self {
+        self.tcp_flags = tcp_flags;
+        self
+    }
+
+    /// Builder: TCP options, padded with zeros to a multiple of 4 bytes. Default: the options of
+    /// [`TcpSynOptions::default`]
+    pub fn with_tcp_options(mut self, tcp_options: Vec<TcpOption>) -> Self {
+        self.tcp_options = tcp_options;
+        self
+    }
+
+    /// Builder: ICMP echo identifier
+    pub fn with_icmp_identifier(mut self, icmp_identifier: u16) -> Self {
+        self.icmp_identifier = icmp_identifier;
+        self
+    }
+
+    /// Builder: ICMP echo sequence number
+    pub fn with_icmp_sequence(mut self, icmp_sequence: u16) -> Self {
+        self.icmp_sequence = icmp_sequence;
+        self
+    }
+
+    /// Builder: Transport payload
+    pub fn with_payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Builder: Total length of the IPv4 packet. The payload is padded with zeros to reach it
+    pub fn with_total_length(mut self, total_length: usize) -> Self {
+        self.total_length = Some(total_length);
+        self
+    }
+
+    /// IPv4 options area, padded with End of Options List to a multiple of 4 bytes
+    fn ip_options_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if self.ip_options.is_empty() {
+            return bytes;
+        }
+        let share = MAX_IPV4_OPTIONS_LENGTH / self.ip_options.len();
+        for option in &self.ip_options {
+            let (option_type, header_length, flag, slot_length) = match option {
+                IpOption::RecordRoute => (IPV4_OPTION_RECORD_ROUTE, 3, None, 4),
+                IpOption::Timestamp => (IPV4_OPTION_TIMESTAMP, 4, Some(0), 4),
+                IpOption::TimestampWithAddresses => (IPV4_OPTION_TIMESTAMP, 4, Some(1), 8),
+            };
+            let slots = share.saturating_sub(header_length) / slot_length;
+            if slots == 0 {
+                continue;
+            }
+            bytes.push(option_type);
+            bytes.push((header_length + slots * slot_length) as u8);
+            bytes.push(header_length as u8 + 1); // pointer to the first free slot
+            bytes.extend(flag);
+            bytes.resize(bytes.len() + slots * slot_length, 0);
+        }
+        bytes.resize((bytes.len() + 3) & !3, 0);
+        bytes
+    }
+
+    /// Length of the TCP options area, including padding
+    fn tcp_options_length(&self) -> usize {
+        let length: usize = self.tcp_options.iter().map(TcpOptionPacket::packet_size).sum();
+        (length + 3) & !3
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+/// IPv4 option (RFC 791) recorded by the routers on the path
+pub enum IpOption {
+    /// Record Route: every router records its outgoing address
+    RecordRoute,
+    /// Internet Timestamp: every router records a timestamp
+    Timestamp,
+    /// Internet Timestamp: every router records its address and a timestamp
+    TimestampWithAddresses,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+/// Tunnel wrapping probes on their way to a tunnel endpoint. The outer header carries the TTL of
+/// the probe, so that the underlay path to the endpoint is traced
+pub enum Encapsulation {
+    /// GRE (RFC 2784) towards the given endpoint
+    Gre(Ipv4Addr),
+    /// IP-in-IP (RFC 2003) towards the given endpoint
+    IpIp(Ipv4Addr),
+}
+
+impl Encapsulation {
+    /// Address of the tunnel endpoint
+    pub fn endpoint(&self) -> Ipv4Addr {
+        match *self {
+            Encapsulation::Gre(endpoint) | Encapsulation::IpIp(endpoint) => endpoint,
+        }
+    }
+
+    /// Protocol announced by the outer IPv4 header
+    pub(crate) fn protocol(&self) -> IpNextHeaderProtocol {
+        match self {
+            Encapsulation::Gre(_) => IpNextHeaderProtocols::Gre,
+            Encapsulation::IpIp(_) => IpNextHeaderProtocols::Ipv4,
+        }
+    }
+
+    /// Length of the headers preceding the encapsulated packet
+    fn header_length(&self) -> usize {
+        match self {
+            Encapsulation::Gre(_) => IPV4_HEADER_LENGTH + GRE_HEADER_LENGTH,
+            Encapsulation::IpIp(_) => IPV4_HEADER_LENGTH,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+/// Set of options carried by TCP SYN probes. The default mimics a Linux SYN (MSS 1460, SACK
+/// permitted, timestamps, window scale 7), which firewalls are least likely to consider anomalous
+pub struct TcpSynOptions {
+    /// Maximum segment size
+    pub mss: Option<u16>,
+    /// SACK permitted
+    pub sack_permitted: bool,
+    /// Timestamps, with the current time in milliseconds as value
+    pub timestamps: bool,
+    /// Window scale shift count
+    pub window_scale: Option<u8>,
+}
+
+impl Default for TcpSynOptions {
+    fn default() -> Self {
+        TcpSynOptions { mss: Some(1460), sack_permitted: true, timestamps: true, window_scale: Some(7) }
+    }
+}
+
+impl TcpSynOptions {
+    /// No options at all
+    pub fn none() -> Self {
+        TcpSynOptions { mss: None, sack_permitted: false, timestamps: false, window_scale: None }
+    }
+
+    /// Options in the order used by Linux, aligned with NOPs
+    pub fn to_options(&self) -> Vec<TcpOption> {
+        let mut options = Vec::new();
+        if let Some(mss) = self.mss {
+            options.push(TcpOption::mss(mss));
+        }
+        match (self.sack_permitted, self.timestamps) {
+            (true, true) => options.push(TcpOption::sack_perm()),
+            (true, false) => options.extend([TcpOption::nop(), TcpOption::nop(), TcpOption::sack_perm()]),
+            (false, true) => options.extend([TcpOption::nop(), TcpOption::nop()]),
+            (false, false) => {}
+        }
+        if self.timestamps {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u32;
+            options.push(TcpOption::timestamp(now, 0));
+        }
+        if let Some(window_scale) = self.window_scale {
+            options.extend([TcpOption::nop(), TcpOption::wscale(window_scale)]);
+        }
+        options
+    }
+}
+
 impl PacketBuilder {
+    /// Creates a builder for `protocol` probes sent from the given addresses
     pub fn new(protocol: Protocol, source_mac: MacAddr, source_ip: Ipv4Addr) -> Self {
-        PacketBuilder { source_mac, source_ip, protocol }
+        PacketBuilder { source_mac, source_ip, protocol, vlan_tags: Vec::new(), encapsulation: None }
     }
 
-    pub fn build_packet(&self, destination_mac:MacAddr, destination_ip: Ipv4Addr, ttl: u8, port: u16, mtu: usize) -> Vec<u8> {
+    /// Builder: Transport protocol of the built probes
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Builder: 802.1Q tags inserted after the Ethernet header, outermost first
+    pub fn with_vlan_tags(mut self, vlan_tags: Vec<VlanTag>) -> Self {
+        self.vlan_tags = vlan_tags;
+        self
+    }
+
+    /// Builder: Tunnel wrapping every built probe
+    pub fn with_encapsulation(mut self, encapsulation: Encapsulation) -> Self {
+        self.encapsulation = Some(encapsulation);
+        self
+    }
+
+    /// Builds the Ethernet frame of `probe`. The IPv4 packet spans the probe's total length if
+    /// set, or just its headers and payload otherwise. VLAN tags are added in front of it, and
+    /// it is wrapped in the tunnel headers if an encapsulation is set
+    pub fn build(&self, destination_mac: MacAddr, destination_ip: Ipv4Addr, probe: &Probe) -> Vec<u8> {
+        let encapsulation_length = self.encapsulation.map_or(0, |encapsulation| encapsulation.header_length());
+        let ip_header_length = IPV4_HEADER_LENGTH + probe.ip_options_bytes().len();
+        let header_length = ip_header_length + match self.protocol {
+            Protocol::UDP => UDP_HEADER_LENGTH,
+            Protocol::TCP => TCP_HEADER_LENGTH + probe.tcp_options_length(),
+            Protocol::ICMP => ICMP_ECHO_HEADER_LENGTH,
+            Protocol::SCTP => SCTP_COMMON_HEADER_LENGTH + SCTP_INIT_CHUNK_LENGTH + Self::sctp_parameter_length(probe.payload.len()),
+            Protocol::DCCP => DCCP_REQUEST_HEADER_LENGTH
+        };
+        // The SCTP payload is carried in a parameter, already counted in the header
+        let payload_length = if self.protocol == Protocol::SCTP { 0 } else { probe.payload.len() };
+        let mut length = probe.total_length.unwrap_or(0).saturating_sub(encapsulation_length)
+            .max(header_length + payload_length);
+        if self.protocol == Protocol::SCTP {
+            // SCTP chunks are padded to 4 bytes
+            length = header_length.max(ip_header_length + ((length - ip_header_length) & !3));
+        }
+
+        let mut buf = vec![0u8; self.link_header_length() + encapsulation_length + length];
+        let mut offset = self.build_ethernet_header(&mut buf, destination_mac);
+        let encapsulated_probe;
+        let probe = match self.encapsulation {
+            Some(encapsulation) => {
+                // Options are recorded by the routers behind the tunnel, on the inner header
+                let outer_probe = probe.clone().with_ip_options(Vec::new());
+                self.build_encapsulation_header(&mut buf[offset..], encapsulation, &outer_probe);
+                offset += encapsulation_length;
+                encapsulated_probe = probe.clone().with_ttl(ENCAPSULATED_TTL);
+                &encapsulated_probe
+            }
+            None => probe
+        };
+        let ip_packet = &mut buf[offset..];
         match self.protocol {
-            Protocol::UDP => Self::build_udp_packet(self.source_mac, self.source_ip, destination_mac, destination_ip, ttl, port, mtu),
-            Protocol::TCP => Self::build_tcp_packet(self.source_mac, self.source_ip, destination_mac, destination_ip, ttl, port, mtu),
-            Protocol::ICMP => Self::build_icmp_packet(self.source_mac, self.source_ip, destination_mac, destination_ip, ttl, mtu)
+            Protocol::UDP => Self::build_udp_packet(ip_packet, self.source_ip, destination_ip, probe),
+            Protocol::TCP => Self::build_tcp_packet(ip_packet, self.source_ip, destination_ip, probe),
+            Protocol::ICMP => Self::build_icmp_packet(ip_packet, self.source_ip, destination_ip, probe),
+            Protocol::SCTP => Self::build_sctp_packet(ip_packet, self.source_ip, destination_ip, probe),
+            Protocol::DCCP => Self::build_dccp_packet(ip_packet, self.source_ip, destination_ip, probe)
         }
+        buf
     }
 
-    /// Create a new UDP packet
-    fn build_udp_packet(source_mac: MacAddr, source_ip: Ipv4Addr, destination_mac:MacAddr, destination_ip: Ipv4Addr, ttl: u8, port: u16, mtu: usize) -> Vec<u8> {
-        let mut buf = [0u8; 1500]; 
-        let mut mut_ethernet_header = MutableEthernetPacket::new(&mut buf).unwrap();
-		//ethernet 14
+    /// Length of the link-layer header preceding the IPv4 packet in built frames
+    pub(crate) fn link_header_length(&self) -> usize {
+        ETHERNET_HEADER_LENGTH + self.vlan_tags.len() * VLAN_TAG_LENGTH
+    }
+
+    /// Offset of the transport header of `probe` in built frames, past the link-layer,
+    /// encapsulation and IPv4 headers
+    pub(crate) fn transport_header_offset(&self, probe: &Probe) -> usize {
+        let encapsulation_length = self.encapsulation.map_or(0, |encapsulation| encapsulation.header_length());
+        self.link_header_length() + encapsulation_length + IPV4_HEADER_LENGTH + probe.ip_options_bytes().len()
+    }
+
+    /// Writes the Ethernet header followed by the configured VLAN tags (outermost first).
+    /// Returns the offset of the IPv4 header
+    fn build_ethernet_header(&self, buf: &mut [u8], destination_mac: MacAddr) -> usize {
+        let mut mut_ethernet_header = MutableEthernetPacket::new(buf).unwrap();
         mut_ethernet_header.set_destination(destination_mac);
-        mut_ethernet_header.set_source(source_mac);
-        mut_ethernet_header.set_ethertype(EtherTypes::Ipv4);
-		//ip header 20 
-        let mut ip_header = MutableIpv4Packet::new(mut_ethernet_header.payload_mut()).unwrap();
+        mut_ethernet_header.set_source(self.source_mac);
+        mut_ethernet_header.set_ethertype(self.tag_ethertype(0));
+
+        let mut offset = ETHERNET_HEADER_LENGTH;
+        for (i, tag) in self.vlan_tags.iter().enumerate() {
+            let mut vlan_header = MutableVlanPacket::new(&mut buf[offset..]).unwrap();
+            vlan_header.set_priority_code_point(ClassOfService::new(tag.priority & 0x7));
+            vlan_header.set_drop_eligible_indicator(0);
+            vlan_header.set_vlan_identifier(tag.id & 0xfff);
+            vlan_header.set_ethertype(self.tag_ethertype(i + 1));
+            offset += VLAN_TAG_LENGTH;
+        }
+        offset
+    }
+
+    /// EtherType announcing the header at `depth` in the tag stack: 802.1ad for the outer tag of
+    /// a QinQ stack, 802.1Q for a customer tag, IPv4 once the stack is exhausted
+    fn tag_ethertype(&self, depth: usize) -> EtherType {
+        if depth >= self.vlan_tags.len() {
+            EtherTypes::Ipv4
+        } else if depth + 1 < self.vlan_tags.len() {
+            EtherTypes::PBridge
+        } else {
+            EtherTypes::Vlan
+        }
+    }
+
+    /// Writes the outer IPv4 header towards the tunnel endpoint, followed by the GRE header if any
+    fn build_encapsulation_header(&self, buf: &mut [u8], encapsulation: Encapsulation, probe: &Probe) {
+        let mut ip_header = Self::build_ipv4_header(buf, self.source_ip, encapsulation.endpoint(), encapsulation.protocol(), probe);
+        if let Encapsulation::Gre(_) = encapsulation {
+            //gre header 4, no checksum, key or sequence number
+            let gre_header = ip_header.payload_mut();
+            gre_header[0..2].fill(0);
+            gre_header[2..4].copy_from_slice(&EtherTypes::Ipv4.0.to_be_bytes());
+        }
+    }
+
+    /// Writes an IPv4 header, with the probe's options, spanning the whole of `buf`
+    fn build_ipv4_header<'a>(buf: &'a mut [u8], source_ip: Ipv4Addr, destination_ip: Ipv4Addr,
+                             protocol: IpNextHeaderProtocol, probe: &Probe) -> MutableIpv4Packet<'a> {
+        let total_length = buf.len() as u16;
+        let options = probe.ip_options_bytes();
+        buf[IPV4_HEADER_LENGTH..IPV4_HEADER_LENGTH + options.len()].copy_from_slice(&options);
+        //ip header 20 + options
+        let mut ip_header = MutableIpv4Packet::new(buf).unwrap();
 
         ip_header.set_version(4);
-        ip_header.set_header_length(5); // 4 * 5 = 20
-        ip_header.set_total_length((mtu - 14) as u16);
-		ip_header.set_flags(Ipv4Flags::DontFragment);
-        ip_header.set_ttl(ttl);
-        ip_header.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip_header.set_header_length(((IPV4_HEADER_LENGTH + options.len()) / 4) as u8);
+        ip_header.set_dscp(probe.tos >> 2);
+        ip_header.set_ecn(probe.tos & 0x3);
+        ip_header.set_total_length(total_length);
+        ip_header.set_identification(probe.ip_id);
+        ip_header.set_flags(probe.ip_flags);
+        ip_header.set_ttl(probe.ttl);
+        ip_header.set_next_level_protocol(protocol);
         ip_header.set_source(source_ip);
         ip_header.set_destination(destination_ip);
         ip_header.set_checksum(pnet::packet::ipv4::checksum(&ip_header.to_immutable()));
+        ip_header
+    }
+
+    /// Source port of `probe`, picked at random from the dynamic range unless set
+    fn source_port(probe: &Probe) -> u16 {
+        probe.source_port.unwrap_or_else(|| rand::thread_rng().gen_range(49152..65535))
+    }
+
+    /// Create a new UDP packet
+    fn build_udp_packet(buf: &mut [u8], source_ip: Ipv4Addr, destination_ip: Ipv4Addr, probe: &Probe) {
+        let length = buf.len();
+        let mut ip_header = Self::build_ipv4_header(buf, source_ip, destination_ip, IpNextHeaderProtocols::Udp, probe);
 
+        let udp_length = length - ip_header.get_header_length() as usize * 4;
         let mut udp_header = MutableUdpPacket::new(ip_header.payload_mut()).unwrap();
-		//udp header 8
-        udp_header.set_source(rand::thread_rng().gen_range(49152..65535));
-        udp_header.set_destination(port);
-        udp_header.set_length((mtu - 14 - 20) as u16);
-        udp_header.set_payload(&vec![0;mtu - 14 - 20 - 8]);
+        //udp header 8
+        udp_header.set_source(Self::source_port(probe));
+        udp_header.set_destination(probe.destination_port);
+        udp_header.set_length(udp_length as u16);
+        udp_header.set_payload(&probe.payload);
         udp_header.set_checksum(pnet::packet::udp::ipv4_checksum(&udp_header.to_immutable(),
                                                                  &source_ip, &destination_ip));
-
-        buf[..mtu].to_vec()
     }
 
-    /// Create a new ICMP packet
-    fn build_icmp_packet(source_mac: MacAddr, source_ip: Ipv4Addr, destination_mac:MacAddr, destination_ip: Ipv4Addr, ttl: u8, mtu: usize) -> Vec<u8> {
-        let mut buf = [0u8; 1500];
-        let mut mut_ethernet_header = MutableEthernetPacket::new(&mut buf).unwrap();
-		//ethernet 14
-        mut_ethernet_header.set_destination(destination_mac);
-        mut_ethernet_header.set_source(source_mac);
-        mut_ethernet_header.set_ethertype(EtherTypes::Ipv4);
-		//ip header 20 
-        let mut ip_header = MutableIpv4Packet::new(mut_ethernet_header.payload_mut()).unwrap();
-
-        ip_header.set_version(4);
-        ip_header.set_header_length(5);
-        ip_header.set_total_length((mtu - 14) as u16);
-		ip_header.set_flags(Ipv4Flags::DontFragment);
-        ip_header.set_ttl(ttl);
-        ip_header.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
-        ip_header.set_source(source_ip);
-        ip_header.set_destination(destination_ip);
-        ip_header.set_checksum(pnet::packet::ipv4::checksum(&ip_header.to_immutable()));
-		//icmp header 28
-        let mut icmp_header = MutableIcmpPacket::new(ip_header.payload_mut()).unwrap();
+    /// Create a new ICMP echo request packet
+    fn build_icmp_packet(buf: &mut [u8], source_ip: Ipv4Addr, destination_ip: Ipv4Addr, probe: &Probe) {
+        let mut ip_header = Self::build_ipv4_header(buf, source_ip, destination_ip, IpNextHeaderProtocols::Icmp, probe);
+        //icmp header 8
+        let mut icmp_header = MutableEchoRequestPacket::new(ip_header.payload_mut()).unwrap();
 
         icmp_header.set_icmp_type(IcmpTypes::EchoRequest);
         icmp_header.set_icmp_code(IcmpCode::new(0));
-        icmp_header.set_payload(&vec![0; mtu - 28 - 20 - 14]);
-        icmp_header.set_checksum(pnet::packet::icmp::checksum(&icmp_header.to_immutable()));
-
-        buf[..mtu].to_vec()
+        icmp_header.set_identifier(probe.icmp_identifier);
+        icmp_header.set_sequence_number(probe.icmp_sequence);
+        icmp_header.set_payload(&probe.payload);
+        let checksum = pnet::packet::icmp::checksum(&IcmpPacket::new(icmp_header.packet_mut()).unwrap());
+        icmp_header.set_checksum(checksum);
     }
 
     /// Create a new TCP packet
-    fn build_tcp_packet(source_mac: MacAddr, source_ip: Ipv4Addr, destination_mac:MacAddr, destination_ip: Ipv4Addr, ttl: u8, port: u16, mtu: usize) -> Vec<u8> {
-        let mut buf = [0u8; 1500];
-        let mut mut_ethernet_header = MutableEthernetPacket::new(&mut buf[..]).unwrap();
-		//ethernet 14
-        mut_ethernet_header.set_destination(destination_mac);
-        mut_ethernet_header.set_source(source_mac);
-        mut_ethernet_header.set_ethertype(EtherTypes::Ipv4);
-		//ip header 20 
-        let mut ip_header = MutableIpv4Packet::new(mut_ethernet_header.payload_mut()).unwrap();
-
-        ip_header.set_version(4);
-        ip_header.set_header_length(5);
-        ip_header.set_total_length((mtu - 14) as u16);
-		ip_header.set_flags(Ipv4Flags::DontFragment);
-        ip_header.set_ttl(ttl);
-        ip_header.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
-        ip_header.set_source(source_ip);
-        ip_header.set_destination(destination_ip);
-        ip_header.set_checksum(pnet::packet::ipv4::checksum(&ip_header.to_immutable()));
-		//tcp header 20
+    fn build_tcp_packet(buf: &mut [u8], source_ip: Ipv4Addr, destination_ip: Ipv4Addr, probe: &Probe) {
+        let mut ip_header = Self::build_ipv4_header(buf, source_ip, destination_ip, IpNextHeaderProtocols::Tcp, probe);
+        //tcp header 20 + options
         let mut tcp_header = MutableTcpPacket::new(ip_header.payload_mut()).unwrap();
 
-        tcp_header.set_source(rand::thread_rng().gen_range(49152..65535));
-        tcp_header.set_destination(port);
-        tcp_header.set_sequence(0);
-        tcp_header.set_acknowledgement(0);
-        tcp_header.set_data_offset(5);
+        tcp_header.set_source(Self::source_port(probe));
+        tcp_header.set_destination(probe.destination_port);
+        tcp_header.set_sequence(probe.tcp_sequence.unwrap_or_else(|| rand::thread_rng().gen()));
+        tcp_header.set_acknowledgement(probe.tcp_acknowledgement);
+        tcp_header.set_data_offset(((TCP_HEADER_LENGTH + probe.tcp_options_length()) / 4) as u8);
         tcp_header.set_reserved(0);
-        tcp_header.set_flags(TcpFlags::SYN);
-        tcp_header.set_window(0);
-        tcp_header.set_payload(&vec![0; mtu - 20 - 20 - 14]);
+        tcp_header.set_flags(probe.tcp_flags);
+        tcp_header.set_window(probe.tcp_window);
+        tcp_header.set_options(&probe.tcp_options);
+        tcp_header.set_payload(&probe.payload);
         tcp_header.set_checksum(pnet::packet::tcp::ipv4_checksum(&tcp_header.to_immutable(),
                                                                  &source_ip, &destination_ip));
+    }
+
+    /// Length of the Padding parameter carrying `payload_length` bytes in an INIT chunk, none if
+    /// there is no payload
+    fn sctp_parameter_length(payload_length: usize) -> usize {
+        if payload_length == 0 { 0 } else { (4 + payload_length + 3) & !3 }
+    }
+
+    /// Create a new SCTP packet with a single INIT chunk. The payload, and the padding up to the
+    /// probe's total length, are carried in a Padding parameter
+    fn build_sctp_packet(buf: &mut [u8], source_ip: Ipv4Addr, destination_ip: Ipv4Addr, probe: &Probe) {
+        let mut ip_header = Self::build_ipv4_header(buf, source_ip, destination_ip, IpNextHeaderProtocols::Sctp, probe);
+        let sctp_packet = ip_header.payload_mut();
+        let mut rng = rand::thread_rng();
+        //sctp common header 12, verification tag 0 for INIT
+        sctp_packet[0..2].copy_from_slice(&Self::source_port(probe).to_be_bytes());
+        sctp_packet[2..4].copy_from_slice(&probe.destination_port.to_be_bytes());
+        sctp_packet[4..12].fill(0);
+
+        //init chunk 20 + parameters
+        let parameter_length = (sctp_packet.len() - SCTP_COMMON_HEADER_LENGTH - SCTP_INIT_CHUNK_LENGTH) & !3;
+        let chunk = &mut sctp_packet[SCTP_COMMON_HEADER_LENGTH..];
+        chunk[0] = SCTP_CHUNK_INIT;
+        chunk[1] = 0;
+        chunk[2..4].copy_from_slice(&((SCTP_INIT_CHUNK_LENGTH + parameter_length) as u16).to_be_bytes());
+        chunk[4..8].copy_from_slice(&rng.gen_range(1..=u32::MAX).to_be_bytes()); // initiate tag
+        chunk[8..12].copy_from_slice(&106496u32.to_be_bytes()); // advertised receiver window, as Linux
+        chunk[12..14].copy_from_slice(&10u16.to_be_bytes()); // outbound streams
+        chunk[14..16].copy_from_slice(&u16::MAX.to_be_bytes()); // inbound streams
+        chunk[16..20].copy_from_slice(&rng.gen::<u32>().to_be_bytes()); // initial TSN
+        if parameter_length >= 4 {
+            let parameter = &mut chunk[SCTP_INIT_CHUNK_LENGTH..];
+            parameter[0..2].copy_from_slice(&SCTP_PARAMETER_PADDING.to_be_bytes());
+            parameter[2..4].copy_from_slice(&(parameter_length as u16).to_be_bytes());
+            let payload_length = probe.payload.len().min(parameter_length - 4);
+            parameter[4..4 + payload_length].copy_from_slice(&probe.payload[..payload_length]);
+        }
+
+        let checksum = crc32c(sctp_packet);
+        sctp_packet[8..12].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// Create a new DCCP-Request packet with an extended sequence number
+    fn build_dccp_packet(buf: &mut [u8], source_ip: Ipv4Addr, destination_ip: Ipv4Addr, probe: &Probe) {
+        let mut ip_header = Self::build_ipv4_header(buf, source_ip, destination_ip, IpNextHeaderProtocols::Dccp, probe);
+        let dccp_packet = ip_header.payload_mut();
+        //dccp generic header 16 + service code 4
+        dccp_packet[0..2].copy_from_slice(&Self::source_port(probe).to_be_bytes());
+        dccp_packet[2..4].copy_from_slice(&probe.destination_port.to_be_bytes());
+        dccp_packet[4] = (DCCP_REQUEST_HEADER_LENGTH / 4) as u8; // data offset
+        dccp_packet[5] = 0; // CCVal and checksum coverage of the whole packet
+        dccp_packet[6..8].fill(0);
+        dccp_packet[8] = (DCCP_TYPE_REQUEST << 1) | 1; // extended sequence numbers
+        dccp_packet[9] = 0;
+        dccp_packet[10..16].copy_from_slice(&rand::thread_rng().gen::<u64>().to_be_bytes()[2..]);
+        dccp_packet[16..20].fill(0); // service code 0 (unspecified)
+        let payload_length = probe.payload.len().min(dccp_packet.len() - DCCP_REQUEST_HEADER_LENGTH);
+        dccp_packet[DCCP_REQUEST_HEADER_LENGTH..DCCP_REQUEST_HEADER_LENGTH + payload_length]
+            .copy_from_slice(&probe.payload[..payload_length]);
+
+        let checksum = pnet::packet::util::ipv4_checksum(dccp_packet, 3, &[], &source_ip, &destination_ip,
+                                                         IpNextHeaderProtocols::Dccp);
+        dccp_packet[6..8].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// CRC32c (Castagnoli) of `data`, as used by the SCTP checksum
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::Packet;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use pnet::packet::udp::UdpPacket;
+    use pnet::packet::tcp::TcpPacket;
+    use pnet::packet::icmp::echo_request::EchoRequestPacket;
+
+    #[test]
+    fn crc32c_matches_rfc3720_vectors() {
+        assert_eq!(crc32c(&[0; 32]), 0x8a91_36aa);
+        assert_eq!(crc32c(&[0xff; 32]), 0x62a8_ab43);
+        assert_eq!(crc32c(&(0..32).collect::<Vec<u8>>()), 0x46dd_794e);
+    }
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    fn build(protocol: Protocol, probe: &Probe) -> Vec<u8> {
+        PacketBuilder::new(protocol, MacAddr::new(2, 0, 0, 0, 0, 1), SOURCE)
+            .build(MacAddr::broadcast(), DESTINATION, probe)
+    }
+
+    fn ip_fields() -> Probe {
+        Probe::default().with_ttl(7).with_ip_id(0x4242).with_ip_flags(Ipv4Flags::DontFragment).with_tos(0xb9)
+    }
+
+    fn assert_ip_fields(frame: &[u8], protocol: IpNextHeaderProtocol) -> Ipv4Packet<'_> {
+        let ip_packet = Ipv4Packet::new(&frame[ETHERNET_HEADER_LENGTH..]).unwrap();
+        assert_eq!(ip_packet.get_ttl(), 7);
+        assert_eq!(ip_packet.get_identification(), 0x4242);
+        assert_eq!(ip_packet.get_flags(), Ipv4Flags::DontFragment);
+        assert_eq!(ip_packet.get_dscp(), 0xb9 >> 2);
+        assert_eq!(ip_packet.get_ecn(), 1);
+        assert_eq!(ip_packet.get_next_level_protocol(), protocol);
+        assert_eq!(ip_packet.get_source(), SOURCE);
+        assert_eq!(ip_packet.get_destination(), DESTINATION);
+        assert_eq!(ip_packet.get_total_length() as usize, frame.len() - ETHERNET_HEADER_LENGTH);
+        assert_eq!(ip_packet.get_checksum(), pnet::packet::ipv4::checksum(&ip_packet));
+        ip_packet
+    }
+
+    #[test]
+    fn udp_probe_carries_setters() {
+        let probe = ip_fields().with_source_port(40000).with_destination_port(53).with_payload(vec![1, 2, 3])
+            .with_total_length(60);
+        let frame = build(Protocol::UDP, &probe);
+        assert_eq!(frame.len(), ETHERNET_HEADER_LENGTH + 60);
+        let ip_packet = assert_ip_fields(&frame, IpNextHeaderProtocols::Udp);
+        let udp_packet = UdpPacket::new(ip_packet.payload()).unwrap();
+        assert_eq!(udp_packet.get_source(), 40000);
+        assert_eq!(udp_packet.get_destination(), 53);
+        assert_eq!(udp_packet.get_length(), 40);
+        assert_eq!(&udp_packet.payload()[..3], &[1, 2, 3]);
+        assert_eq!(udp_packet.get_checksum(), pnet::packet::udp::ipv4_checksum(&udp_packet, &SOURCE, &DESTINATION));
+    }
+
+    #[test]
+    fn tcp_probe_carries_setters() {
+        let probe = ip_fields().with_source_port(40000).with_destination_port(443).with_tcp_sequence(0xdead_beef)
+            .with_tcp_acknowledgement(0x0102_0304).with_tcp_window(1024).with_tcp_flags(TcpFlags::ACK)
+            .with_tcp_options(vec![TcpOption::mss(1200), TcpOption::nop(), TcpOption::nop(), TcpOption::sack_perm()])
+            .with_payload(vec![9; 4]);
+        let frame = build(Protocol::TCP, &probe);
+        let ip_packet = assert_ip_fields(&frame, IpNextHeaderProtocols::Tcp);
+        let tcp_packet = TcpPacket::new(ip_packet.payload()).unwrap();
+        assert_eq!(tcp_packet.get_source(), 40000);
+        assert_eq!(tcp_packet.get_destination(), 443);
+        assert_eq!(tcp_packet.get_sequence(), 0xdead_beef);
+        assert_eq!(tcp_packet.get_acknowledgement(), 0x0102_0304);
+        assert_eq!(tcp_packet.get_window(), 1024);
+        assert_eq!(tcp_packet.get_flags(), TcpFlags::ACK);
+        assert_eq!(tcp_packet.get_data_offset(), 7);
+        assert_eq!(&ip_packet.payload()[TCP_HEADER_LENGTH..28], &[2, 4, 0x04, 0xb0, 1, 1, 4, 2]);
+        assert_eq!(tcp_packet.payload(), &[9; 4]);
+        assert_eq!(tcp_packet.get_checksum(), pnet::packet::tcp::ipv4_checksum(&tcp_packet, &SOURCE, &DESTINATION));
+    }
+
+    #[test]
+    fn icmp_probe_carries_setters() {
+        let probe = ip_fields().with_icmp_identifier(0x1111).with_icmp_sequence(0x2222).with_payload(vec![5; 6]);
+        let frame = build(Protocol::ICMP, &probe);
+        let ip_packet = assert_ip_fields(&frame, IpNextHeaderProtocols::Icmp);
+        let echo_request = EchoRequestPacket::new(ip_packet.payload()).unwrap();
+        assert_eq!(echo_request.get_icmp_type(), IcmpTypes::EchoRequest);
+        assert_eq!(echo_request.get_identifier(), 0x1111);
+        assert_eq!(echo_request.get_sequence_number(), 0x2222);
+        assert_eq!(echo_request.payload(), &[5; 6]);
+        let icmp_packet = IcmpPacket::new(ip_packet.payload()).unwrap();
+        assert_eq!(icmp_packet.get_checksum(), pnet::packet::icmp::checksum(&icmp_packet));
+    }
+
+    #[test]
+    fn sctp_probe_is_an_init_chunk() {
+        let frame = build(Protocol::SCTP, &ip_fields().with_source_port(40000).with_destination_port(80).with_payload((1..=8).collect()));
+        let ip_packet = assert_ip_fields(&frame, IpNextHeaderProtocols::Sctp);
+        let sctp_packet = ip_packet.payload();
+        assert_eq!(sctp_packet.len(), SCTP_COMMON_HEADER_LENGTH + SCTP_INIT_CHUNK_LENGTH + 12);
+        assert_eq!(sctp_packet[0..8], [0x9c, 0x40, 0, 80, 0, 0, 0, 0]);
+        assert_eq!(sctp_packet[12..16], [SCTP_CHUNK_INIT, 0, 0, 32]);
+        assert_ne!(sctp_packet[16..20], [0; 4]);
+        assert_eq!(sctp_packet[32..36], [0x80, 0x05, 0, 12]);
+        assert_eq!(sctp_packet[36..], [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let mut unchecked = sctp_packet.to_vec();
+        unchecked[8..12].fill(0);
+        assert_eq!(sctp_packet[8..12], crc32c(&unchecked).to_le_bytes());
+    }
+
+    #[test]
+    fn sctp_probe_pads_to_total_length() {
+        let frame = build(Protocol::SCTP, &Probe::default().with_payload(vec![1, 2, 3]).with_total_length(99));
+        let ip_packet = Ipv4Packet::new(&frame[ETHERNET_HEADER_LENGTH..]).unwrap();
+        assert_eq!(ip_packet.get_total_length(), 96);
+        assert_eq!(ip_packet.payload()[14..16], [0, 96 - 20 - 12]);
+        assert_eq!(ip_packet.payload()[34..36], [0, 96 - 20 - 12 - 20]);
+        assert_eq!(build(Protocol::SCTP, &Probe::default()).len(), ETHERNET_HEADER_LENGTH + 20 + 12 + 20);
+    }
+
+    #[test]
+    fn dccp_probe_is_a_request() {
+        let frame = build(Protocol::DCCP, &ip_fields().with_source_port(40000).with_destination_port(5001).with_payload(vec![7; 4]));
+        let ip_packet = assert_ip_fields(&frame, IpNextHeaderProtocols::Dccp);
+        let dccp_packet = ip_packet.payload();
+        assert_eq!(dccp_packet.len(), DCCP_REQUEST_HEADER_LENGTH + 4);
+        assert_eq!(dccp_packet[0..6], [0x9c, 0x40, 0x13, 0x89, 5, 0]);
+        assert_eq!(dccp_packet[8] >> 1 & 0xf, DCCP_TYPE_REQUEST);
+        assert_eq!(dccp_packet[8] & 1, 1);
+        assert_eq!(dccp_packet[16..20], [0; 4]);
+        assert_eq!(dccp_packet[20..], [7; 4]);
+        let checksum = pnet::packet::util::ipv4_checksum(dccp_packet, 3, &[], &SOURCE, &DESTINATION, IpNextHeaderProtocols::Dccp);
+        assert_eq!(dccp_packet[6..8], checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn ip_options_share_the_options_area() {
+        let options = |ip_options: Vec<IpOption>| Probe::default().with_ip_options(ip_options).ip_options_bytes();
+
+        let record_route = options(vec![IpOption::RecordRoute]);
+        assert_eq!(record_route.len(), MAX_IPV4_OPTIONS_LENGTH);
+        assert_eq!(record_route[..3], [IPV4_OPTION_RECORD_ROUTE, 39, 4]);
+        assert!(record_route[3..].iter().all(|byte| *byte == 0));
+
+        // Two options get 20 bytes each: 4 addresses, and 2 address and timestamp pairs
+        let split = options(vec![IpOption::RecordRoute, IpOption::TimestampWithAddresses]);
+        assert_eq!(split.len(), MAX_IPV4_OPTIONS_LENGTH);
+        assert_eq!(split[..3], [IPV4_OPTION_RECORD_ROUTE, 19, 4]);
+        assert_eq!(split[19..23], [IPV4_OPTION_TIMESTAMP, 20, 5, 1]);
+        assert_eq!(split[39], 0);
+
+        let timestamps = options(vec![IpOption::Timestamp]);
+        assert_eq!(timestamps[..4], [IPV4_OPTION_TIMESTAMP, 40, 5, 0]);
+
+        let frame = build(Protocol::UDP, &Probe::default().with_ip_options(vec![IpOption::RecordRoute, IpOption::Timestamp]));
+        let ip_packet = Ipv4Packet::new(&frame[ETHERNET_HEADER_LENGTH..]).unwrap();
+        assert_eq!(ip_packet.get_header_length(), 15);
+        assert_eq!(ip_packet.get_options_raw(), &options(vec![IpOption::RecordRoute, IpOption::Timestamp])[..]);
+    }
+
+    const ENDPOINT: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+
+    /// Outer and inner IPv4 packets of a UDP probe wrapped in `encapsulation`
+    fn tunnel(encapsulation: Encapsulation) -> (Vec<u8>, Vec<u8>) {
+        let probe = Probe::default().with_ttl(3).with_ip_id(0x4242).with_ip_options(vec![IpOption::RecordRoute]);
+        let frame = PacketBuilder::new(Protocol::UDP, MacAddr::new(2, 0, 0, 0, 0, 1), SOURCE)
+            .with_encapsulation(encapsulation)
+            .build(MacAddr::broadcast(), DESTINATION, &probe);
+        let outer = frame[ETHERNET_HEADER_LENGTH..].to_vec();
+        let inner = outer[encapsulation.header_length()..].to_vec();
+        (outer, inner)
+    }
+
+    /// Checks the inner packet of a tunnel: sent to the destination with options and a TTL high
+    /// enough to get there once decapsulated
+    fn assert_inner_packet(inner: &[u8]) {
+        let ip_packet = Ipv4Packet::new(inner).unwrap();
+        assert_eq!(ip_packet.get_ttl(), ENCAPSULATED_TTL);
+        assert_eq!(ip_packet.get_identification(), 0x4242);
+        assert_eq!(ip_packet.get_destination(), DESTINATION);
+        assert_eq!(ip_packet.get_next_level_protocol(), IpNextHeaderProtocols::Udp);
+        assert_eq!(ip_packet.get_options_raw()[0], IPV4_OPTION_RECORD_ROUTE);
+        assert_eq!(ip_packet.get_total_length() as usize, inner.len());
+    }
+
+    #[test]
+    fn gre_probe_wraps_inner_packet() {
+        let (outer, inner) = tunnel(Encapsulation::Gre(ENDPOINT));
+        let ip_packet = Ipv4Packet::new(&outer).unwrap();
+        assert_eq!(ip_packet.get_header_length(), 5);
+        assert_eq!(ip_packet.get_ttl(), 3);
+        assert_eq!(ip_packet.get_identification(), 0x4242);
+        assert_eq!(ip_packet.get_destination(), ENDPOINT);
+        assert_eq!(ip_packet.get_next_level_protocol(), IpNextHeaderProtocols::Gre);
+        assert_eq!(ip_packet.get_total_length() as usize, outer.len());
+        assert_eq!(ip_packet.payload()[..GRE_HEADER_LENGTH], [0, 0, 0x08, 0x00]);
+        assert_inner_packet(&inner);
+    }
+
+    #[test]
+    fn ipip_probe_wraps_inner_packet() {
+        let (outer, inner) = tunnel(Encapsulation::IpIp(ENDPOINT));
+        let ip_packet = Ipv4Packet::new(&outer).unwrap();
+        assert_eq!(ip_packet.get_header_length(), 5);
+        assert_eq!(ip_packet.get_ttl(), 3);
+        assert_eq!(ip_packet.get_destination(), ENDPOINT);
+        assert_eq!(ip_packet.get_next_level_protocol(), IpNextHeaderProtocols::Ipv4);
+        assert_eq!(ip_packet.payload(), &inner[..]);
+        assert_inner_packet(&inner);
+    }
 
-        buf[..mtu].to_vec()
+    #[test]
+    fn vlan_tags_and_protocol_builders_apply() {
+        let builder = PacketBuilder::new(Protocol::UDP, MacAddr::new(2, 0, 0, 0, 0, 1), SOURCE)
+            .with_protocol(Protocol::ICMP)
+            .with_vlan_tags(vec![VlanTag { id: 100, priority: 0 }]);
+        let frame = builder.build(MacAddr::broadcast(), DESTINATION, &Probe::default());
+        assert_eq!(&frame[12..14], &EtherTypes::Vlan.0.to_be_bytes());
+        assert_eq!(frame[ETHERNET_HEADER_LENGTH + VLAN_TAG_LENGTH + 9], IpNextHeaderProtocols::Icmp.0);
     }
 }
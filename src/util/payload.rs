@@ -0,0 +1,153 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use rand::Rng;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+/// Reserved QUIC version following the 0x?a?a?a?a pattern, which servers must answer with a
+/// Version Negotiation packet
+const QUIC_GREASE_VERSION: u32 = 0x1a2a_3a4a;
+/// Minimum size of a UDP datagram carrying a QUIC Initial packet
+const QUIC_INITIAL_LENGTH: usize = 1200;
+
+#[derive(Clone, Debug, PartialEq)]
+/// Well-formed application payload carried by UDP probes to a service port, so that probes get
+/// through service-aware firewalls and the application at the destination answers
+pub enum ApplicationPayload {
+    /// DNS query for the A record of the given name
+    Dns(String),
+    /// NTP version 4 client request
+    Ntp,
+    /// QUIC Initial with a reserved version, triggering a Version Negotiation from the server
+    Quic,
+}
+
+impl ApplicationPayload {
+    /// Well-known port of the service
+    pub fn port(&self) -> u16 {
+        match self {
+            ApplicationPayload::Dns(_) => 53,
+            ApplicationPayload::Ntp => 123,
+            ApplicationPayload::Quic => 443,
+        }
+    }
+
+    /// Builds a new payload. Identifiers (DNS ID, QUIC connection IDs) are random
+    pub fn build(&self) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        match self {
+            ApplicationPayload::Dns(name) => {
+                let mut payload = Vec::with_capacity(name.len() + 18);
+                payload.extend_from_slice(&rng.gen::<u16>().to_be_bytes());
+                payload.extend_from_slice(&[0x01, 0x00]); // standard query, recursion desired
+                payload.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question
+                for label in name.split('.').filter(|label| !label.is_empty()) {
+                    let label = &label.as_bytes()[..label.len().min(63)];
+                    payload.push(label.len() as u8);
+                    payload.extend_from_slice(label);
+                }
+                payload.push(0);
+                payload.extend_from_slice(&[0, 1, 0, 1]); // type A, class IN
+                payload
+            }
+            ApplicationPayload::Ntp => {
+                let mut payload = vec![0u8; 48];
+                payload[0] = (4 << 3) | 3; // version 4, client mode
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                let fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+                payload[40..44].copy_from_slice(&((now.as_secs() + NTP_UNIX_OFFSET) as u32).to_be_bytes());
+                payload[44..48].copy_from_slice(&(fraction as u32).to_be_bytes());
+                payload
+            }
+            ApplicationPayload::Quic => {
+                let mut payload = vec![0u8; QUIC_INITIAL_LENGTH];
+                payload[0] = 0xc0; // long header, Initial
+                payload[1..5].copy_from_slice(&QUIC_GREASE_VERSION.to_be_bytes());
+                payload[5] = 8;
+                rng.fill(&mut payload[6..14]); // destination connection ID
+                payload[14] = 8;
+                rng.fill(&mut payload[15..23]); // source connection ID
+                payload
+            }
+        }
+    }
+
+    /// Checks whether `payload`, received from the destination, is an answer of the application
+    /// to `request`, a payload built earlier. DNS responses must carry the ID of the query
+    pub fn recognizes(&self, request: &[u8], payload: &[u8]) -> bool {
+        match self {
+            ApplicationPayload::Dns(_) =>
+                payload.len() >= 12 && payload[2] & 0x80 != 0 && request.get(0..2) == Some(&payload[0..2]),
+            ApplicationPayload::Ntp => payload.len() >= 48 && payload[0] & 0x7 == 4,
+            ApplicationPayload::Quic => payload.len() >= 5 && payload[0] & 0x80 != 0 && payload[1..5] == [0; 4],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_dns_query() {
+        let query = ApplicationPayload::Dns("www.example.com.".to_string()).build();
+        assert_eq!(query[2..12], [0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(query[12..], *b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+    }
+
+    #[test]
+    fn recognizes_dns_response_to_query() {
+        let dns = ApplicationPayload::Dns("example.com".to_string());
+        let query = dns.build();
+        let mut response = query.clone();
+        response[2] |= 0x80;
+        assert!(dns.recognizes(&query, &response));
+        assert!(!dns.recognizes(&query, &query));
+        assert!(!dns.recognizes(&query, &response[..11]));
+
+        response[0] ^= 0xff;
+        assert!(!dns.recognizes(&query, &response));
+    }
+
+    #[test]
+    fn builds_and_recognizes_ntp() {
+        let request = ApplicationPayload::Ntp.build();
+        assert_eq!(request.len(), 48);
+        assert_eq!(request[0], 0x23);
+        assert_ne!(request[40..48], [0; 8]);
+
+        let mut response = request.clone();
+        response[0] = 0x24;
+        assert!(ApplicationPayload::Ntp.recognizes(&request, &response));
+        assert!(!ApplicationPayload::Ntp.recognizes(&request, &request));
+        assert!(!ApplicationPayload::Ntp.recognizes(&request, &response[..47]));
+    }
+
+    #[test]
+    fn builds_and_recognizes_quic() {
+        let request = ApplicationPayload::Quic.build();
+        assert_eq!(request.len(), QUIC_INITIAL_LENGTH);
+        assert_eq!(request[0..6], [0xc0, 0x1a, 0x2a, 0x3a, 0x4a, 8]);
+        assert_eq!(request[14], 8);
+        assert_ne!(request[6..14], request[15..23]);
+
+        let version_negotiation = [0x80, 0, 0, 0, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 1];
+        assert!(ApplicationPayload::Quic.recognizes(&request, &version_negotiation));
+        assert!(!ApplicationPayload::Quic.recognizes(&request, &request));
+    }
+}
@@ -0,0 +1,511 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Minimal support for the pcap and pcapng capture file formats
+
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// LINKTYPE_NULL: BSD loopback encapsulation
+const LINKTYPE_NULL: u32 = 0;
+/// LINKTYPE_ETHERNET: IEEE 802.3 Ethernet
+pub(crate) const LINKTYPE_ETHERNET: u32 = 1;
+/// LINKTYPE_RAW: raw IPv4/IPv6 without link-layer header
+const LINKTYPE_RAW: u32 = 101;
+/// LINKTYPE_LINUX_SLL: Linux "cooked" capture (`tcpdump -i any`)
+const LINKTYPE_LINUX_SLL: u32 = 113;
+/// LINKTYPE_IPV4: raw IPv4 without link-layer header
+const LINKTYPE_IPV4: u32 = 228;
+
+/// pcapng block types
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_PACKET: u32 = 0x0000_0002;
+const BLOCK_SIMPLE_PACKET: u32 = 0x0000_0003;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+
+/// pcapng byte-order magic
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+/// pcapng option codes
+const OPTION_END: u16 = 0;
+const OPTION_EPB_FLAGS: u16 = 2;
+const OPTION_IF_TSRESOL: u16 = 9;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+/// Direction of a captured frame relative to the capturing host
+pub(crate) enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Single captured frame
+pub(crate) struct Record {
+    pub timestamp: SystemTime,
+    pub link_type: u32,
+    pub direction: Option<Direction>,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Returns the frame with its link-layer header rewritten as Ethernet, which is what the
+    /// packet handlers expect. Frames of unsupported link types yield `None`.
+    pub fn to_ethernet(&self) -> Option<Vec<u8>> {
+        let ipv4_frame = |packet: &[u8]| {
+            let mut frame = vec![0u8; 12];
+            frame.extend_from_slice(&[0x08, 0x00]);
+            frame.extend_from_slice(packet);
+            frame
+        };
+        match self.link_type {
+            LINKTYPE_ETHERNET => Some(self.data.clone()),
+            LINKTYPE_RAW | LINKTYPE_IPV4 => match self.data.first() {
+                Some(b) if b >> 4 == 4 => Some(ipv4_frame(&self.data)),
+                _ => None
+            },
+            LINKTYPE_NULL => match self.data.get(..4) {
+                Some(family) if u32::from_le_bytes([family[0], family[1], family[2], family[3]]) == 2
+                    || u32::from_be_bytes([family[0], family[1], family[2], family[3]]) == 2 => Some(ipv4_frame(&self.data[4..])),
+                _ => None
+            },
+            LINKTYPE_LINUX_SLL => match self.data.get(14..16) {
+                Some(protocol) => {
+                    let mut frame = vec![0u8; 12];
+                    frame.extend_from_slice(protocol);
+                    frame.extend_from_slice(&self.data[16..]);
+                    Some(frame)
+                }
+                None => None
+            },
+            _ => None
+        }
+    }
+
+    /// Direction recorded by the capture, if any
+    pub fn direction(&self) -> Option<Direction> {
+        if self.direction.is_some() {
+            return self.direction;
+        }
+        // Linux cooked captures carry the packet type in the first two bytes
+        match (self.link_type, self.data.get(..2)) {
+            (LINKTYPE_LINUX_SLL, Some([0, 4])) => Some(Direction::Outbound),
+            (LINKTYPE_LINUX_SLL, Some(_)) => Some(Direction::Inbound),
+            _ => None
+        }
+    }
+}
+
+/// Cursor over a byte buffer with a runtime byte order
+struct Reader<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn u16(&self, offset: usize) -> Result<u16, &'static str> {
+        let b = self.bytes.get(offset..offset + 2).ok_or("truncated capture")?;
+        let b = [b[0], b[1]];
+        Ok(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, &'static str> {
+        let b = self.bytes.get(offset..offset + 4).ok_or("truncated capture")?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Ok(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    fn slice(&self, offset: usize, length: usize) -> Result<&'a [u8], &'static str> {
+        self.bytes.get(offset..offset.checked_add(length).ok_or("truncated capture")?).ok_or("truncated capture")
+    }
+}
+
+/// Parses a pcap or pcapng file into the list of its records
+pub(crate) fn read_records(bytes: &[u8]) -> Result<Vec<Record>, &'static str> {
+    let magic = bytes.get(..4).ok_or("truncated capture")?;
+    match u32::from_le_bytes([magic[0], magic[1], magic[2], magic[3]]) {
+        0xa1b2_c3d4 => read_pcap(Reader { bytes, big_endian: false }, 1_000),
+        0xd4c3_b2a1 => read_pcap(Reader { bytes, big_endian: true }, 1_000),
+        0xa1b2_3c4d => read_pcap(Reader { bytes, big_endian: false }, 1),
+        0x4d3c_b2a1 => read_pcap(Reader { bytes, big_endian: true }, 1),
+        BLOCK_SECTION_HEADER => read_pcapng(bytes),
+        _ => Err("unknown capture format")
+    }
+}
+
+/// Parses a classic pcap file. `nanos_per_unit` is the resolution of the fractional timestamp
+fn read_pcap(reader: Reader, nanos_per_unit: u32) -> Result<Vec<Record>, &'static str> {
+    let link_type = reader.u32(20)? & 0xffff;
+    let mut records = Vec::new();
+    let mut offset = 24;
+    while offset < reader.bytes.len() {
+        let seconds = reader.u32(offset)?;
+        let fraction = reader.u32(offset + 4)?;
+        let captured_length = reader.u32(offset + 8)? as usize;
+        let data = reader.slice(offset + 16, captured_length)?;
+        records.push(Record {
+            timestamp: UNIX_EPOCH + Duration::new(seconds as u64, 0) + Duration::from_nanos(fraction as u64 * nanos_per_unit as u64),
+            link_type,
+            direction: None,
+            data: data.to_vec(),
+        });
+        offset += 16 + captured_length;
+    }
+    Ok(records)
+}
+
+/// Link type and timestamp resolution (`if_tsresol`) of a pcapng interface
+#[derive(Clone, Copy)]
+struct Interface {
+    link_type: u32,
+    tsresol: u8,
+}
+
+impl Interface {
+    fn timestamp(&self, units: u64) -> SystemTime {
+        let exponent = (self.tsresol & 0x7f) as u32;
+        let nanos = if self.tsresol & 0x80 != 0 {
+            (units as u128 * 1_000_000_000) >> exponent
+        } else if exponent <= 9 {
+            units as u128 * 10u128.pow(9 - exponent)
+        } else {
+            10u128.checked_pow(exponent - 9).map_or(0, |divisor| units as u128 / divisor)
+        };
+        UNIX_EPOCH + Duration::from_nanos(nanos as u64)
+    }
+}
+
+/// Calls `f` with the code and value of every option between `offset` and `end`
+fn for_each_option(reader: &Reader, mut offset: usize, end: usize, mut f: impl FnMut(u16, &[u8])) -> Result<(), &'static str> {
+    while offset + 4 <= end {
+        let code = reader.u16(offset)?;
+        let length = reader.u16(offset + 2)? as usize;
+        if code == OPTION_END {
+            break;
+        }
+        f(code, reader.slice(offset + 4, length)?);
+        offset += 4 + ((length + 3) & !3);
+    }
+    Ok(())
+}
+
+/// Parses a pcapng file, honouring per-interface link types and timestamp resolutions
+fn read_pcapng(bytes: &[u8]) -> Result<Vec<Record>, &'static str> {
+    let mut reader = Reader { bytes, big_endian: false };
+    let mut interfaces = Vec::<Interface>::new();
+    let mut records = Vec::new();
+    let mut last_timestamp = UNIX_EPOCH;
+    let mut offset = 0;
+    while offset < bytes.len() {
+        // The section header block type reads the same in either byte order
+        if reader.u32(offset)? == BLOCK_SECTION_HEADER {
+            let byte_order = reader.slice(offset + 8, 4)?;
+            reader.big_endian = byte_order == [0x1a, 0x2b, 0x3c, 0x4d];
+            interfaces.clear();
+        }
+        let block_type = reader.u32(offset)?;
+        let block_length = reader.u32(offset + 4)? as usize;
+        if block_length < 12 || !block_length.is_multiple_of(4) {
+            return Err("malformed pcapng block");
+        }
+        let body = offset + 8;
+        let end = offset + block_length - 4;
+        reader.slice(offset, block_length)?;
+
+        match block_type {
+            BLOCK_INTERFACE_DESCRIPTION => {
+                let mut interface = Interface { link_type: reader.u16(body)? as u32, tsresol: 6 };
+                for_each_option(&reader, body + 8, end, |code, value| {
+                    if code == OPTION_IF_TSRESOL && !value.is_empty() {
+                        interface.tsresol = value[0];
+                    }
+                })?;
+                interfaces.push(interface);
+            }
+            BLOCK_ENHANCED_PACKET | BLOCK_PACKET => {
+                let interface_id = if block_type == BLOCK_PACKET { reader.u16(body)? as usize } else { reader.u32(body)? as usize };
+                let interface = *interfaces.get(interface_id).ok_or("packet for unknown interface")?;
+                let units = ((reader.u32(body + 4)? as u64) << 32) | reader.u32(body + 8)? as u64;
+                let captured_length = reader.u32(body + 12)? as usize;
+                let data = reader.slice(body + 20, captured_length)?;
+                let mut direction = None;
+                if block_type == BLOCK_ENHANCED_PACKET {
+                    let options = body + 20 + ((captured_length + 3) & !3);
+                    for_each_option(&reader, options, end, |code, value| {
+                        if code == OPTION_EPB_FLAGS && value.len() == 4 {
+                            let flags = Reader { bytes: value, big_endian: reader.big_endian }.u32(0).unwrap_or(0);
+                            direction = match flags & 0x3 {
+                                1 => Some(Direction::Inbound),
+                                2 => Some(Direction::Outbound),
+                                _ => None
+                            };
+                        }
+                    })?;
+                }
+                last_timestamp = interface.timestamp(units);
+                records.push(Record { timestamp: last_timestamp, link_type: interface.link_type, direction, data: data.to_vec() });
+            }
+            BLOCK_SIMPLE_PACKET => {
+                let interface = *interfaces.first().ok_or("packet for unknown interface")?;
+                let original_length = reader.u32(body)? as usize;
+                let data = reader.slice(body + 4, original_length.min(end.saturating_sub(body + 4)))?;
+                records.push(Record { timestamp: last_timestamp, link_type: interface.link_type, direction: None, data: data.to_vec() });
+            }
+            _ => {}
+        }
+        offset += block_length;
+    }
+    Ok(records)
+}
+
+/// Writer producing a pcapng file with a single Ethernet interface and nanosecond timestamps
+pub(crate) struct Writer {
+    output: Box<dyn Write + Send>,
+    header_written: bool,
+}
+
+impl Writer {
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        Writer { output, header_written: false }
+    }
+
+    /// Appends a frame, writing the section header and interface description first if needed
+    pub fn write(&mut self, timestamp: SystemTime, direction: Direction, data: &[u8]) -> std::io::Result<()> {
+        if !self.header_written {
+            let mut header = Vec::new();
+            write_block(&mut header, BLOCK_SECTION_HEADER, |body| {
+                body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+                body.extend_from_slice(&1u16.to_le_bytes());
+                body.extend_from_slice(&0u16.to_le_bytes());
+                // Section length is not known upfront
+                body.extend_from_slice(&(-1i64).to_le_bytes());
+            });
+            write_block(&mut header, BLOCK_INTERFACE_DESCRIPTION, |body| {
+                body.extend_from_slice(&(LINKTYPE_ETHERNET as u16).to_le_bytes());
+                body.extend_from_slice(&0u16.to_le_bytes());
+                body.extend_from_slice(&0u32.to_le_bytes());
+                write_option(body, OPTION_IF_TSRESOL, &[9]);
+                write_option(body, OPTION_END, &[]);
+            });
+            self.output.write_all(&header)?;
+            self.header_written = true;
+        }
+
+        let nanos = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let flags: u32 = match direction {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        };
+        let mut block = Vec::new();
+        write_block(&mut block, BLOCK_ENHANCED_PACKET, |body| {
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(nanos as u32).to_le_bytes());
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            body.resize((body.len() + 3) & !3, 0);
+            write_option(body, OPTION_EPB_FLAGS, &flags.to_le_bytes());
+            write_option(body, OPTION_END, &[]);
+        });
+        self.output.write_all(&block)?;
+        self.output.flush()
+    }
+}
+
+/// Appends a pcapng block of type `block_type` whose body is produced by `f`
+fn write_block(buf: &mut Vec<u8>, block_type: u32, f: impl FnOnce(&mut Vec<u8>)) {
+    let mut body = Vec::new();
+    f(&mut body);
+    let length = (12 + body.len()) as u32;
+    buf.extend_from_slice(&block_type.to_le_bytes());
+    buf.extend_from_slice(&length.to_le_bytes());
+    buf.extend_from_slice(&body);
+    buf.extend_from_slice(&length.to_le_bytes());
+}
+
+/// Appends a pcapng option padded to 32 bits
+fn write_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Output shared with the test once moved into a [`Writer`]
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Classic pcap file with a record per (seconds, fraction, data) triple
+    fn pcap(magic: u32, link_type: u32, records: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&magic.to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&4u16.to_le_bytes());
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&65535u32.to_le_bytes());
+        bytes.extend_from_slice(&link_type.to_le_bytes());
+        for (seconds, fraction, data) in records {
+            bytes.extend_from_slice(&seconds.to_le_bytes());
+            bytes.extend_from_slice(&fraction.to_le_bytes());
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    fn section_header(bytes: &mut Vec<u8>) {
+        write_block(bytes, BLOCK_SECTION_HEADER, |body| {
+            body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+            body.extend_from_slice(&1u16.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&(-1i64).to_le_bytes());
+        });
+    }
+
+    fn interface_description(bytes: &mut Vec<u8>, link_type: u32, tsresol: Option<u8>) {
+        write_block(bytes, BLOCK_INTERFACE_DESCRIPTION, |body| {
+            body.extend_from_slice(&(link_type as u16).to_le_bytes());
+            body.extend_from_slice(&[0; 6]);
+            if let Some(tsresol) = tsresol {
+                write_option(body, OPTION_IF_TSRESOL, &[tsresol]);
+            }
+            write_option(body, OPTION_END, &[]);
+        });
+    }
+
+    fn enhanced_packet(bytes: &mut Vec<u8>, interface_id: u32, units: u64, data: &[u8]) {
+        write_block(bytes, BLOCK_ENHANCED_PACKET, |body| {
+            body.extend_from_slice(&interface_id.to_le_bytes());
+            body.extend_from_slice(&((units >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(units as u32).to_le_bytes());
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            body.resize((body.len() + 3) & !3, 0);
+        });
+    }
+
+    #[test]
+    fn writer_round_trip() {
+        let output = SharedBuffer::default();
+        let mut writer = Writer::new(Box::new(output.clone()));
+        let sent = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+        let received = sent + Duration::from_nanos(1_000_001);
+        writer.write(sent, Direction::Outbound, &[1, 2, 3]).unwrap();
+        writer.write(received, Direction::Inbound, &[4, 5, 6, 7, 8]).unwrap();
+
+        let records = read_records(&output.0.lock().unwrap()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].timestamp, sent);
+        assert_eq!(records[0].link_type, LINKTYPE_ETHERNET);
+        assert_eq!(records[0].direction(), Some(Direction::Outbound));
+        assert_eq!(records[0].data, vec![1, 2, 3]);
+        assert_eq!(records[1].timestamp, received);
+        assert_eq!(records[1].direction(), Some(Direction::Inbound));
+        assert_eq!(records[1].data, vec![4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn writer_reports_write_errors() {
+        struct FailingOutput;
+
+        impl Write for FailingOutput {
+            fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disk full"))
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut writer = Writer::new(Box::new(FailingOutput));
+        assert!(writer.write(UNIX_EPOCH, Direction::Outbound, &[0; 14]).is_err());
+    }
+
+    #[test]
+    fn reads_pcap_timestamp_resolutions() {
+        let micros = read_records(&pcap(0xa1b2_c3d4, LINKTYPE_ETHERNET, &[(10, 250_000, &[0; 14])])).unwrap();
+        assert_eq!(micros[0].timestamp, UNIX_EPOCH + Duration::from_millis(10_250));
+        assert_eq!(micros[0].direction(), None);
+
+        let nanos = read_records(&pcap(0xa1b2_3c4d, LINKTYPE_ETHERNET, &[(10, 250_000, &[0; 14])])).unwrap();
+        assert_eq!(nanos[0].timestamp, UNIX_EPOCH + Duration::new(10, 250_000));
+    }
+
+    #[test]
+    fn reads_pcapng_interfaces_and_sections() {
+        let mut bytes = Vec::new();
+        section_header(&mut bytes);
+        interface_description(&mut bytes, LINKTYPE_ETHERNET, None);
+        interface_description(&mut bytes, LINKTYPE_RAW, Some(0x80 | 10));
+        enhanced_packet(&mut bytes, 0, 1_500_000, &[0; 14]);
+        enhanced_packet(&mut bytes, 1, 3 << 10, &[0x45, 0]);
+        // A new section forgets the interfaces of the previous one
+        section_header(&mut bytes);
+        interface_description(&mut bytes, LINKTYPE_IPV4, Some(3));
+        enhanced_packet(&mut bytes, 0, 2_500, &[0x45, 0]);
+
+        let records = read_records(&bytes).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].timestamp, UNIX_EPOCH + Duration::from_millis(1_500));
+        assert_eq!(records[0].link_type, LINKTYPE_ETHERNET);
+        assert_eq!(records[1].timestamp, UNIX_EPOCH + Duration::from_secs(3));
+        assert_eq!(records[1].link_type, LINKTYPE_RAW);
+        assert_eq!(records[1].to_ethernet().unwrap()[12..], [0x08, 0x00, 0x45, 0]);
+        assert_eq!(records[2].timestamp, UNIX_EPOCH + Duration::from_millis(2_500));
+        assert_eq!(records[2].link_type, LINKTYPE_IPV4);
+
+        let mut bytes = Vec::new();
+        section_header(&mut bytes);
+        enhanced_packet(&mut bytes, 0, 0, &[0; 14]);
+        assert_eq!(read_records(&bytes).err(), Some("packet for unknown interface"));
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let mut bytes = pcap(0xa1b2_c3d4, LINKTYPE_ETHERNET, &[(1, 0, &[0; 14])]);
+        bytes.truncate(bytes.len() - 1);
+        assert_eq!(read_records(&bytes).err(), Some("truncated capture"));
+
+        let mut bytes = Vec::new();
+        section_header(&mut bytes);
+        interface_description(&mut bytes, LINKTYPE_ETHERNET, None);
+        enhanced_packet(&mut bytes, 0, 0, &[0; 14]);
+        bytes.truncate(bytes.len() - 4);
+        assert_eq!(read_records(&bytes).err(), Some("truncated capture"));
+
+        assert_eq!(read_records(&[0xd4, 0xc3]).err(), Some("truncated capture"));
+        assert_eq!(read_records(&[0; 24]).err(), Some("unknown capture format"));
+    }
+}
@@ -0,0 +1,219 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::util::pcap::{self, Direction, Record};
+use pnet::datalink::{channel, DataLinkReceiver, DataLinkSender, NetworkInterface};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// How long a single read on the datalink channel may block before the deadline is rechecked
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Source and sink of raw Ethernet frames used by traceroute
+pub trait Transport: Send {
+    /// Transmits a single frame
+    fn send(&mut self, frame: &[u8]);
+
+    /// Returns the next received frame together with its arrival time, or `None`
+    /// if nothing arrived before `deadline`
+    fn recv(&mut self, deadline: SystemTime) -> Option<(Vec<u8>, SystemTime)>;
+
+    /// Current time as seen by the transport
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// Whether frames go out in real time, so that probes must be paced. Replays aren't
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+/// Live transport sending and capturing frames on a network interface
+pub struct DatalinkTransport {
+    interface: NetworkInterface,
+    channel: Option<(Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>)>,
+}
+
+impl DatalinkTransport {
+    /// Creates a transport on `interface`. The capture is opened on first use
+    pub fn new(interface: NetworkInterface) -> Self {
+        DatalinkTransport { interface, channel: None }
+    }
+
+    /// Opens the datalink channel if it isn't open yet
+    fn channel(&mut self) -> &mut (Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>) {
+        let interface = &self.interface;
+        self.channel.get_or_insert_with(|| {
+            let config = pnet::datalink::Config { read_timeout: Some(READ_TIMEOUT), ..Default::default() };
+            match channel(interface, config) {
+                Ok(pnet::datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
+                Ok(_) => panic!("libtraceroute: unhandled util type"),
+                Err(e) => panic!("libtraceroute: unable to create util: {}", e),
+            }
+        })
+    }
+}
+
+impl Transport for DatalinkTransport {
+    fn send(&mut self, frame: &[u8]) {
+        let (tx, _) = self.channel();
+        tx.send_to(frame, None);
+    }
+
+    fn recv(&mut self, deadline: SystemTime) -> Option<(Vec<u8>, SystemTime)> {
+        let (_, rx) = self.channel();
+        while SystemTime::now() < deadline {
+            match rx.next() {
+                Ok(packet) => return Some((packet.to_vec(), SystemTime::now())),
+                Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => panic!("libtraceroute: unable to receive packet: {}", e),
+            }
+        }
+        None
+    }
+}
+
+/// Offline transport replaying the frames of a pcap or pcapng capture.
+///
+/// Sent probes are discarded and time is taken from the capture: every received frame is
+/// delivered at its recorded timestamp, and a probe is considered sent at the timestamp of the
+/// matching outgoing frame if the capture contains one. RTTs are therefore only meaningful for
+/// captures that include both directions.
+pub struct PcapReplay {
+    records: VecDeque<Record>,
+    clock: SystemTime,
+    destination: Option<Ipv4Addr>,
+}
+
+impl PcapReplay {
+    /// Loads the capture at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Loads a capture held in memory
+    pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Self> {
+        let records = pcap::read_records(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let clock = records.first().map_or(SystemTime::UNIX_EPOCH, |record| record.timestamp);
+        Ok(PcapReplay { records: records.into(), clock, destination: None })
+    }
+
+    /// Checks whether `record` is a probe sent by the traced host towards the current destination
+    fn is_outgoing(&self, record: &Record) -> bool {
+        if record.direction() == Some(Direction::Inbound) {
+            return false;
+        }
+        match (record.to_ethernet(), self.destination) {
+            (Some(frame), Some(destination)) => ipv4_destination(&frame) == Some(destination),
+            _ => false
+        }
+    }
+}
+
+impl Transport for PcapReplay {
+    fn send(&mut self, frame: &[u8]) {
+        self.destination = ipv4_destination(frame);
+        if let Some(record) = self.records.front() {
+            if self.is_outgoing(record) {
+                self.clock = record.timestamp;
+                self.records.pop_front();
+            }
+        }
+    }
+
+    fn recv(&mut self, deadline: SystemTime) -> Option<(Vec<u8>, SystemTime)> {
+        while let Some(record) = self.records.front() {
+            if record.timestamp > deadline || self.is_outgoing(record) {
+                break;
+            }
+            let record = self.records.pop_front().unwrap();
+            if record.timestamp > self.clock {
+                self.clock = record.timestamp;
+            }
+            if let Some(frame) = record.to_ethernet() {
+                return Some((frame, record.timestamp));
+            }
+        }
+        self.clock = deadline;
+        None
+    }
+
+    fn now(&self) -> SystemTime {
+        self.clock
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+}
+
+/// Returns the destination of the IPv4 packet carried by an Ethernet frame, skipping VLAN tags
+fn ipv4_destination(frame: &[u8]) -> Option<Ipv4Addr> {
+    let mut offset = 12;
+    loop {
+        match frame.get(offset..offset + 2)? {
+            [0x81, 0x00] | [0x88, 0xa8] | [0x91, 0x00] => offset += 4,
+            [0x08, 0x00] => break,
+            _ => return None
+        }
+    }
+    let destination = frame.get(offset + 18..offset + 22)?;
+    Some(Ipv4Addr::new(destination[0], destination[1], destination[2], destination[3]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{capture, exchange};
+    use std::time::UNIX_EPOCH;
+
+    fn ethernet(packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    #[test]
+    fn replays_replies_on_virtual_clock() {
+        let exchanges = [exchange([10, 0, 0, 1], 11, 0), exchange([10, 0, 0, 2], 11, 0)];
+        let mut replay = PcapReplay::from_bytes(&capture(&exchanges)).unwrap();
+        let at = |millis| UNIX_EPOCH + Duration::from_millis(millis);
+        assert_eq!(replay.now(), at(1_000));
+
+        replay.send(&ethernet(&exchanges[0].0));
+        assert_eq!(replay.recv(at(1_005)), None);
+        assert_eq!(replay.now(), at(1_005));
+        assert_eq!(replay.recv(at(1_500)), Some((ethernet(&exchanges[0].1), at(1_010))));
+        // The next recorded probe is not replied to before it is sent
+        assert_eq!(replay.recv(at(1_500)), None);
+
+        replay.send(&ethernet(&exchanges[1].0));
+        assert_eq!(replay.now(), at(1_100));
+        assert_eq!(replay.recv(at(2_000)), Some((ethernet(&exchanges[1].1), at(1_110))));
+        assert_eq!(replay.recv(at(2_000)), None);
+        assert_eq!(replay.now(), at(2_000));
+    }
+
+    #[test]
+    fn rejects_unknown_format() {
+        assert_eq!(PcapReplay::from_bytes(&[0; 24]).err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+    }
+}
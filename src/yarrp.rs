@@ -0,0 +1,389 @@
+/*
+   Copyright 2020 Ilya Grishkov
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::Config;
+use crate::response::{ProbeResponse, ResponseKind};
+use crate::util::Protocol;
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::tcp::TcpFlags;
+use pnet::util::MacAddr;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// Probes sent per second unless configured otherwise
+const DEFAULT_RATE: u32 = 1000;
+
+#[derive(Clone, Debug, PartialEq)]
+/// Reply to a probe of a [`Yarrp`] sweep, attributed from the contents of the reply alone
+pub struct YarrpRecord {
+    /// Destination of the probe
+    pub target: Ipv4Addr,
+    /// Time-To-Live of the probe
+    pub ttl: u8,
+    /// Node that answered
+    pub responder: Ipv4Addr,
+    /// Round-Trip Time, with millisecond resolution
+    pub rtt: Duration,
+    /// Decoded response
+    pub response: ProbeResponse,
+}
+
+/// Pseudo-random permutation of `0..size`, from a full-period linear congruential generator
+/// over the next power of two
+struct Permutation {
+    size: u64,
+    mask: u64,
+    multiplier: u64,
+    increment: u64,
+    state: u64,
+    remaining: u64,
+}
+
+impl Permutation {
+    fn new(size: u64) -> Self {
+        let mut rng = rand::thread_rng();
+        Permutation {
+            size,
+            mask: size.next_power_of_two() - 1,
+            // Full period modulo a power of two: multiplier = 1 (mod 4) and an odd increment
+            multiplier: rng.gen_range(0..u64::MAX / 4) * 4 + 1,
+            increment: rng.gen_range(0..u64::MAX / 2) * 2 + 1,
+            state: rng.gen(),
+            remaining: size,
+        }
+    }
+}
+
+impl Iterator for Permutation {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            self.state = self.state.wrapping_mul(self.multiplier).wrapping_add(self.increment) & self.mask;
+            if self.state < self.size {
+                self.remaining -= 1;
+                return Some(self.state);
+            }
+        }
+    }
+}
+
+/// Stateless, randomized topology sweep in the style of Yarrp.
+///
+/// Every (target, TTL) pair from the first TTL up to the maximum number of hops is probed once,
+/// in a pseudo-random order and at a fixed rate, without waiting for replies. Each probe carries
+/// its TTL in the IPv4 identification, a checksum of its target in the source port (ICMP
+/// identifier) and its send time in the TCP sequence number, UDP checksum or ICMP sequence number,
+/// so that replies can be attributed without keeping any state. Records are streamed by
+/// iterating, as replies arrive
+pub struct Yarrp {
+    config: Config,
+    mac: MacAddr,
+    targets: Vec<Ipv4Addr>,
+    rate: u32,
+    permutation: Option<Permutation>,
+    started: Option<SystemTime>,
+    probes_sent: u64,
+    last_sent: Option<SystemTime>,
+    records: VecDeque<YarrpRecord>,
+}
+
+impl Yarrp {
+    /// Creates new instance of Yarrp sending every probe through the next hop at `macstr`.
+    /// Only UDP, TCP and ICMP probes can be attributed statelessly
+    pub fn new(macstr: &str, config: Config) -> Result<Self, &'static str> {
+        if !matches!(config.channel.protocol(), Protocol::UDP | Protocol::TCP | Protocol::ICMP) {
+            return Err("stateless probing supports UDP, TCP and ICMP only");
+        }
+        Ok(Yarrp {
+            config,
+            mac: MacAddr::from_str(macstr).unwrap_or(MacAddr::zero()),
+            targets: Vec::new(),
+            rate: DEFAULT_RATE,
+            permutation: None,
+            started: None,
+            probes_sent: 0,
+            last_sent: None,
+            records: VecDeque::new(),
+        })
+    }
+
+    /// Builder: Target to probe
+    pub fn with_target(mut self, addrstr: &str) -> Self {
+        self.add_target(addrstr);
+        self
+    }
+
+    /// Builder: Probes sent per second. Default: 1000
+    pub fn with_rate(mut self, rate: u32) -> Self {
+        self.rate = rate.max(1);
+        self
+    }
+
+    /// Queues a target to probe. Targets added once the sweep started are ignored
+    pub fn add_target(&mut self, addrstr: &str) {
+        self.targets.push(Ipv4Addr::from_str(addrstr).unwrap_or(Ipv4Addr::new(0, 0, 0, 0)));
+    }
+
+    /// Number of probes sent so far
+    pub fn probes_sent(&self) -> u64 {
+        self.probes_sent
+    }
+
+    /// TTLs probed for every target
+    fn ttls(&self) -> (u8, u8) {
+        let first_ttl = self.config.ttl.max(1);
+        let last_ttl = self.config.max_hops.clamp(first_ttl as u32, u8::MAX as u32) as u8;
+        (first_ttl, last_ttl)
+    }
+
+    /// Milliseconds from the start of the sweep to `time`
+    fn elapsed_ms(&self, time: SystemTime) -> u64 {
+        self.started
+            .and_then(|started| time.duration_since(started).ok())
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+
+    /// Sends the next probe of the permutation, returning `false` once all were sent
+    fn send_next(&mut self) -> bool {
+        let (first_ttl, last_ttl) = self.ttls();
+        let ttls = (last_ttl - first_ttl) as u64 + 1;
+        let index = match self.permutation.as_mut().and_then(Iterator::next) {
+            Some(index) => index,
+            None => return false
+        };
+        let target = self.targets[(index / ttls) as usize];
+        let ttl = first_ttl + (index % ttls) as u8;
+
+        let frame = self.frame(target, ttl, self.elapsed_ms(self.config.channel.now()));
+        self.config.channel.transmit(&frame);
+        self.probes_sent += 1;
+        self.last_sent = Some(self.config.channel.now());
+        true
+    }
+
+    /// Builds the probe to `target` with `ttl`, sent `elapsed` milliseconds into the sweep
+    fn frame(&self, target: Ipv4Addr, ttl: u8, elapsed: u64) -> Vec<u8> {
+        let checksum = target_checksum(target);
+        let probe = self.config.channel.probe().clone()
+            .with_ttl(ttl)
+            .with_ip_id(ttl as u16);
+        match self.config.channel.protocol() {
+            Protocol::TCP => {
+                let mut probe = probe
+                    .with_source_port(checksum)
+                    .with_destination_port(self.config.port)
+                    .with_tcp_sequence(((ttl as u32) << 24) | (elapsed as u32 & 0xff_ffff));
+                if probe.tcp_flags & TcpFlags::SYN == 0 {
+                    probe = probe.with_tcp_options(Vec::new());
+                }
+                if probe.tcp_flags & TcpFlags::ACK != 0 {
+                    probe = probe.with_tcp_acknowledgement(rand::thread_rng().gen_range(1..=u32::MAX));
+                }
+                self.config.channel.build(self.mac, target, &probe)
+            }
+            Protocol::ICMP => {
+                let probe = probe
+                    .with_icmp_identifier(checksum)
+                    .with_icmp_sequence(elapsed as u16)
+                    .with_payload(vec![ttl]);
+                self.config.channel.build(self.mac, target, &probe)
+            }
+            _ => {
+                // Two payload bytes are chosen so that the UDP checksum equals the send time
+                let probe = probe
+                    .with_source_port(checksum)
+                    .with_destination_port(self.config.port);
+                let frame = self.config.channel.build(self.mac, target, &probe.clone().with_payload(vec![0, 0]));
+                let offset = self.config.channel.transport_header_offset(&probe) + 6;
+                let unadjusted = u16::from_be_bytes([frame[offset], frame[offset + 1]]);
+                let adjustment = ones_complement_add(!(elapsed as u16), unadjusted);
+                self.config.channel.build(self.mac, target, &probe.with_payload(adjustment.to_be_bytes().to_vec()))
+            }
+        }
+    }
+
+    /// Attributes `response` to the probe it answers, from the fields encoded in the probe
+    fn decode(&self, response: ProbeResponse, received: SystemTime) -> Option<YarrpRecord> {
+        let (target, ttl, send_time, bits) = match (&response.quoted_ip_header, response.kind) {
+            (Some(header), _) => {
+                let quote = response.quoted_transport_header.get(..8)?;
+                let field = |offset: usize| u16::from_be_bytes([quote[offset], quote[offset + 1]]);
+                let (checksum, send_time, bits) = match self.config.channel.protocol() {
+                    Protocol::TCP => (field(0), u32::from_be_bytes([0, quote[5], quote[6], quote[7]]), 24),
+                    Protocol::ICMP => (field(4), field(6) as u32, 16),
+                    _ => (field(0), field(6) as u32, 16)
+                };
+                if checksum != target_checksum(header.destination) {
+                    return None;
+                }
+                (header.destination, u8::try_from(header.identification).ok()?, send_time, bits)
+            }
+            (None, ResponseKind::Tcp { destination_port, flags, acknowledgement, .. }) if flags & TcpFlags::ACK != 0 => {
+                if destination_port != target_checksum(response.responder) {
+                    return None;
+                }
+                let sequence = acknowledgement.wrapping_sub(1);
+                (response.responder, (sequence >> 24) as u8, sequence & 0xff_ffff, 24)
+            }
+            (None, ResponseKind::Icmp { icmp_type: IcmpTypes::EchoReply, .. }) => {
+                let echoed = response.payload.get(..5)?;
+                if u16::from_be_bytes([echoed[0], echoed[1]]) != target_checksum(response.responder) {
+                    return None;
+                }
+                (response.responder, echoed[4], u16::from_be_bytes([echoed[2], echoed[3]]) as u32, 16)
+            }
+            _ => return None
+        };
+        // The send time wrapped around, so the probe is taken to be the latest one sent with it
+        let rtt = self.elapsed_ms(received).wrapping_sub(send_time as u64) & ((1 << bits) - 1);
+        Some(YarrpRecord { target, ttl, responder: response.responder, rtt: Duration::from_millis(rtt), response })
+    }
+}
+
+impl Iterator for Yarrp {
+    type Item = YarrpRecord;
+
+    /// Sends probes when they are due and returns the next attributed reply. Ends once every
+    /// probe was sent and the timeout passed since the last one
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.permutation.is_none() {
+            let (first_ttl, last_ttl) = self.ttls();
+            self.permutation = Some(Permutation::new(self.targets.len() as u64 * ((last_ttl - first_ttl) as u64 + 1)));
+            self.started = Some(self.config.channel.now());
+        }
+        loop {
+            if let Some(record) = self.records.pop_front() {
+                return Some(record);
+            }
+            let remaining = self.permutation.as_ref().map_or(0, |permutation| permutation.remaining);
+            let deadline = match (self.started, self.last_sent) {
+                (Some(started), _) if remaining > 0 =>
+                    started + Duration::from_secs_f64(self.probes_sent as f64 / self.rate as f64),
+                (_, Some(last_sent)) => last_sent + self.config.timeout,
+                _ => return None
+            };
+            if self.config.channel.now() >= deadline {
+                if remaining > 0 && self.send_next() {
+                    continue;
+                }
+                return None;
+            }
+            if let Some((response, received)) = self.config.channel.recv_until(deadline) {
+                if let Some(record) = self.decode(response, received) {
+                    self.records.push_back(record);
+                }
+            }
+        }
+    }
+}
+
+/// Checksum of a target carried by its probes, to recognize replies quoting a rewritten destination
+fn target_checksum(target: Ipv4Addr) -> u16 {
+    let octets = target.octets();
+    !ones_complement_add(u16::from_be_bytes([octets[0], octets[1]]), u16::from_be_bytes([octets[2], octets[3]]))
+}
+
+/// One's complement sum of two 16-bit words
+fn ones_complement_add(a: u16, b: u16) -> u16 {
+    let sum = a as u32 + b as u32;
+    ((sum & 0xffff) + (sum >> 16)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VlanTag;
+    use crate::response::parse_ipv4_packet;
+    use crate::tests::{config, ipv4_packet, PROBER, TARGET};
+    use crate::util::packet_builder::IpOption;
+    use pnet::packet::udp::{self, UdpPacket};
+    use std::time::UNIX_EPOCH;
+
+    const ELAPSED: u64 = 1234;
+    const RTT: u64 = 56;
+
+    fn yarrp(config: Config) -> Yarrp {
+        let mut yarrp = Yarrp::new("02:00:00:00:00:02", config).unwrap();
+        yarrp.started = Some(UNIX_EPOCH + Duration::from_secs(1000));
+        yarrp
+    }
+
+    /// Time exceeded from a router, quoting the IPv4 header of the probe in `frame` and 8 more bytes
+    fn time_exceeded(frame: &[u8], link_header_length: usize) -> ProbeResponse {
+        let packet = &frame[link_header_length..];
+        let quote_length = (packet[0] & 0xf) as usize * 4 + 8;
+        let mut icmp = vec![11, 0, 0, 0, 0, 0, 0, 0];
+        icmp.extend_from_slice(&packet[..quote_length]);
+        parse_ipv4_packet(&ipv4_packet([10, 0, 0, 1], PROBER, 1, &icmp)).unwrap()
+    }
+
+    fn assert_decodes(yarrp: &Yarrp, response: ProbeResponse, ttl: u8) {
+        let received = yarrp.started.unwrap() + Duration::from_millis(ELAPSED + RTT);
+        let record = yarrp.decode(response, received).unwrap();
+        assert_eq!(record.target, Ipv4Addr::from(TARGET));
+        assert_eq!(record.ttl, ttl);
+        assert_eq!(record.responder, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(record.rtt, Duration::from_millis(RTT));
+    }
+
+    #[test]
+    fn encodes_and_decodes_udp() {
+        let config = config(30).with_vlan(VlanTag::new(100, 0)).with_ip_options(vec![IpOption::RecordRoute]);
+        let yarrp = yarrp(config);
+        let frame = yarrp.frame(Ipv4Addr::from(TARGET), 7, ELAPSED);
+
+        let packet = &frame[18..];
+        let header_length = (packet[0] & 0xf) as usize * 4;
+        let udp = UdpPacket::new(&packet[header_length..]).unwrap();
+        assert_eq!(udp.get_checksum(), ELAPSED as u16);
+        assert_eq!(udp::ipv4_checksum(&udp, &Ipv4Addr::UNSPECIFIED, &Ipv4Addr::from(TARGET)), udp.get_checksum());
+
+        assert_decodes(&yarrp, time_exceeded(&frame, 18), 7);
+    }
+
+    #[test]
+    fn encodes_and_decodes_tcp() {
+        let yarrp = yarrp(config(30).with_protocol(Protocol::TCP));
+        let frame = yarrp.frame(Ipv4Addr::from(TARGET), 12, ELAPSED);
+        assert_decodes(&yarrp, time_exceeded(&frame, 14), 12);
+    }
+
+    #[test]
+    fn encodes_and_decodes_icmp() {
+        let yarrp = yarrp(config(30).with_protocol(Protocol::ICMP));
+        let frame = yarrp.frame(Ipv4Addr::from(TARGET), 3, ELAPSED);
+        assert_decodes(&yarrp, time_exceeded(&frame, 14), 3);
+    }
+
+    #[test]
+    fn ignores_replies_for_other_targets() {
+        let yarrp = yarrp(config(30));
+        let frame = yarrp.frame(Ipv4Addr::from(TARGET), 7, ELAPSED);
+        let mut response = time_exceeded(&frame, 14);
+        if let Some(header) = response.quoted_ip_header.as_mut() {
+            header.destination = Ipv4Addr::new(192, 0, 2, 2);
+        }
+        assert!(yarrp.decode(response, UNIX_EPOCH).is_none());
+    }
+}